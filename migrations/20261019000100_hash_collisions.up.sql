@@ -0,0 +1,12 @@
+-- Add up migration script here
+create table hash_collision
+(
+    id        bigserial primary key,
+    kind      text        not null,
+    hashed_id bigint      not null,
+    stored    jsonb       not null,
+    incoming  jsonb       not null,
+    seen_at   timestamptz not null default now()
+);
+
+create index on hash_collision (hashed_id);
@@ -0,0 +1,18 @@
+-- Add up migration script here
+create table sample_bucket
+(
+    executable_id bigint references executable (id) on delete cascade deferrable initially deferred not null,
+    stack_node_id bigint references stack_node (id) on delete cascade deferrable initially deferred not null,
+    -- start of the minute the samples were taken in.
+    bucket        timestamptz not null,
+    sample_count  bigint      not null,
+    primary key (stack_node_id, bucket)
+);
+
+create index on sample_bucket (executable_id, bucket);
+
+-- everything from before buckets existed gets attributed to when it was last updated.
+insert into sample_bucket(executable_id, stack_node_id, bucket, sample_count)
+select n.executable_id, n.id, date_trunc('minute', coalesce(e.updated_at, now())), n.sample_count
+from stack_node n
+         inner join executable e on e.id = n.executable_id;
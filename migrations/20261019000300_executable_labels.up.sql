@@ -0,0 +1,10 @@
+-- Add up migration script here
+create table executable_label
+(
+    executable_id bigint references executable (id) on delete cascade deferrable initially deferred not null,
+    key           text not null,
+    value         text not null,
+    primary key (executable_id, key)
+);
+
+create index on executable_label (key, value);
@@ -0,0 +1,40 @@
+-- Add down migration script here
+drop function findRegressions(start_time timestamptz, end_time timestamptz, only_basename text, min_samples bigint, min_pct_change double precision);
+
+create or replace function findRegressions(start_time timestamp default CURRENT_DATE-1)
+    returns table
+            (
+                basename text,
+                "a.build_id" text,
+                "b.build_id" text,
+                file text,
+                symbol text,
+                pct_diff numeric
+            )
+    language plpgsql
+as
+$$
+begin
+    return query with symPctPerVersionPerBinary as (select exe.*,
+                                                           sum(sn.sample_count) / exe.sample_count as normalized_presence,
+                                                           snd.*,
+                                                           snd.id as snd_id,
+                                                           exe.id as exe_id
+                                                    from executable exe
+                                                             inner join
+                                                         stack_node sn on sn.executable_id = exe.id
+                                                             inner join stack_node_data snd on sn.stack_node_data_id = snd.id
+                                                    group by exe.id, snd.id)
+                 select a.basename, a.build_id, b.build_id, a.file, a.symbol,
+                        (b.normalized_presence - a.normalized_presence) / a.normalized_presence * 100 as pct_diff
+                 from symPctPerVersionPerBinary a cross join symPctPerVersionPerBinary b
+                 where a.basename = b.basename and
+                         a.exe_id != b.exe_id and
+                         a.snd_id = b.snd_id
+                   and b.normalized_presence - a.normalized_presence > 0
+                   and b.created_at > a.created_at
+                   and b.created_at > start_time
+                   and a.created_at > start_time
+                 order by pct_diff desc;
+end;
+$$;
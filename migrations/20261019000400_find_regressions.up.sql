@@ -0,0 +1,60 @@
+-- Add up migration script here
+drop function findRegressions(start_time timestamp);
+
+-- same idea as before, but w/ the executable ids (so results can link to flamegraphs), sample
+-- counts, and the filters the api needs pushed down.
+create or replace function findRegressions(start_time timestamptz default CURRENT_DATE-1,
+                                           end_time timestamptz default null,
+                                           only_basename text default null,
+                                           min_samples bigint default 0,
+                                           min_pct_change double precision default 0)
+    returns table
+            (
+                basename text,
+                base_id bigint,
+                base_build_id text,
+                target_id bigint,
+                target_build_id text,
+                file text,
+                symbol text,
+                base_samples bigint,
+                target_samples bigint,
+                pct_diff double precision
+            )
+    language plpgsql
+as
+$$
+begin
+    return query with symPctPerVersionPerBinary as (select exe.id as exe_id,
+                                                           exe.basename as exe_basename,
+                                                           exe.build_id as exe_build_id,
+                                                           exe.created_at as exe_created_at,
+                                                           sum(sn.sample_count)::bigint as samples,
+                                                           (sum(sn.sample_count) / exe.sample_count)::double precision as normalized_presence,
+                                                           snd.id as snd_id,
+                                                           snd.file as snd_file,
+                                                           snd.symbol as snd_symbol
+                                                    from executable exe
+                                                             inner join
+                                                         stack_node sn on sn.executable_id = exe.id
+                                                             inner join stack_node_data snd on sn.stack_node_data_id = snd.id
+                                                    where exe.sample_count > 0
+                                                      and exe.created_at > start_time
+                                                      and (end_time is null or exe.created_at < end_time)
+                                                      and (only_basename is null or exe.basename = only_basename)
+                                                    group by exe.id, snd.id)
+                 select a.exe_basename, a.exe_id, a.exe_build_id, b.exe_id, b.exe_build_id, a.snd_file, a.snd_symbol,
+                        a.samples, b.samples,
+                        (b.normalized_presence - a.normalized_presence) / a.normalized_presence * 100
+                 from symPctPerVersionPerBinary a cross join symPctPerVersionPerBinary b
+                 where a.exe_basename = b.exe_basename and
+                         a.exe_id != b.exe_id and
+                         a.snd_id = b.snd_id
+                   and b.normalized_presence - a.normalized_presence > 0
+                   and b.exe_created_at > a.exe_created_at
+                   and a.samples >= min_samples
+                   and b.samples >= min_samples
+                   and (b.normalized_presence - a.normalized_presence) / a.normalized_presence * 100 >= min_pct_change
+                 order by 10 desc;
+end;
+$$;
@@ -0,0 +1,2 @@
+-- Add down migration script here
+drop index stack_node_stack_node_data_id;
@@ -0,0 +1,7 @@
+-- Add up migration script here
+-- retention deletes frames nothing points at anymore, which is a scan of stack_node per frame w/o this.
+-- migrations run in a transaction, so this is a plain create index and blocks writes to stack_node
+-- (i.e. ingest) until it's built. on a big install, build it beforehand w/
+--   create index concurrently stack_node_stack_node_data_id on stack_node (stack_node_data_id);
+-- and this does nothing.
+create index if not exists stack_node_stack_node_data_id on stack_node (stack_node_data_id);
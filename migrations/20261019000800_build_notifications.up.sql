@@ -0,0 +1,9 @@
+-- Add up migration script here
+-- builds the regression webhooks were told about, so racing first batches only post once.
+create table build_notification
+(
+    basename    text        not null,
+    build_id    text        not null,
+    notified_at timestamptz not null default now(),
+    primary key (basename, build_id)
+);
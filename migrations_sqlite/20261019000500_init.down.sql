@@ -0,0 +1,7 @@
+-- Add down migration script here
+drop table hash_collision;
+drop table sample_bucket;
+drop table executable_label;
+drop table stack_node;
+drop table executable;
+drop table stack_node_data;
@@ -0,0 +1,69 @@
+-- Add up migration script here
+-- everything postgres ends up w/ after its migrations, minus the plpgsql. timestamps are
+-- rfc3339 text, which sorts right as long as they're all written by sqlx.
+create table stack_node_data
+(
+    id          integer primary key,
+    symbol      text not null,
+    file        text,
+    line_number integer
+);
+
+create table executable
+(
+    id                  integer primary key,
+    event               text    not null,
+    build_id            text,
+    basename            text    not null,
+    updated_at          text,
+    created_at          text,
+    sample_count        integer not null,
+    raw_data_size       integer not null,
+    processed_data_size integer not null
+);
+
+create index executable_basename on executable (basename, created_at);
+
+create table stack_node
+(
+    id                 integer primary key,
+    parent_id          integer references stack_node (id) on delete cascade deferrable initially deferred,
+    stack_node_data_id integer not null references stack_node_data (id) on delete cascade deferrable initially deferred,
+    executable_id      integer not null references executable (id) on delete cascade deferrable initially deferred,
+    sample_count       integer not null
+);
+
+create index stack_node_executable on stack_node (executable_id);
+create index stack_node_data_ref on stack_node (stack_node_data_id);
+
+create table executable_label
+(
+    executable_id integer not null references executable (id) on delete cascade deferrable initially deferred,
+    key           text    not null,
+    value         text    not null,
+    primary key (executable_id, key)
+);
+
+create table sample_bucket
+(
+    executable_id integer not null references executable (id) on delete cascade deferrable initially deferred,
+    stack_node_id integer not null references stack_node (id) on delete cascade deferrable initially deferred,
+    -- start of the minute the samples were taken in.
+    bucket        text    not null,
+    sample_count  integer not null,
+    primary key (stack_node_id, bucket)
+);
+
+create index sample_bucket_executable on sample_bucket (executable_id, bucket);
+
+create table hash_collision
+(
+    id        integer primary key autoincrement,
+    kind      text    not null,
+    hashed_id integer not null,
+    stored    text    not null,
+    incoming  text    not null,
+    seen_at   text    not null
+);
+
+create index hash_collision_hashed_id on hash_collision (hashed_id);
@@ -0,0 +1,217 @@
+use anyhow::{bail, Context, Result};
+use regex::Regex;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+use crate::defs::EventType;
+
+// config for `cli agent`. every interval_secs, each target is matched against what's running
+// and every match gets profiled for window_secs, uploaded as its own time-stamped profile.
+//
+//   interval_secs = 60
+//   window_secs = 10
+//
+//   [labels]
+//   region = "us-east"
+//
+//   [[target]]
+//   name = "demo"
+//   comm = "^demo$"
+//
+//   [[target]]
+//   name = "api"
+//   version = "1.2.3"
+//   cmdline = "java .*-jar api.jar"
+//   cgroup = "/system.slice/api.service"
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct AgentConfig {
+    #[serde(default = "default_interval_secs")]
+    pub interval_secs: u64,
+    #[serde(default = "default_window_secs")]
+    pub window_secs: u64,
+    // these fall back to whatever was passed on the command line.
+    pub url: Option<String>,
+    pub sample_freq: Option<u64>,
+    pub event_type: Option<EventType>,
+    // added to every profile, on top of --label (and the host labels w/ --host-labels).
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+    #[serde(rename = "target", default)]
+    pub targets: Vec<AgentTarget>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct AgentTarget {
+    // reported as the basename of every process this matches.
+    pub name: String,
+    pub version: Option<String>,
+    // regex on /proc/<pid>/comm.
+    pub comm: Option<String>,
+    // regex on /proc/<pid>/cmdline, args joined w/ spaces.
+    pub cmdline: Option<String>,
+    // any of the cgroup paths in /proc/<pid>/cgroup is this cgroup or one below it.
+    pub cgroup: Option<String>,
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+}
+
+fn default_interval_secs() -> u64 {
+    60
+}
+
+fn default_window_secs() -> u64 {
+    10
+}
+
+impl AgentConfig {
+    pub fn load(path: &Path) -> Result<AgentConfig> {
+        let raw = fs::read_to_string(path)
+            .with_context(|| format!("unable to read agent config {}", path.display()))?;
+        let config: AgentConfig = toml::from_str(&raw)
+            .with_context(|| format!("unable to parse agent config {}", path.display()))?;
+        if config.window_secs == 0 || config.window_secs > config.interval_secs {
+            bail!("window_secs must be between 1 and interval_secs");
+        }
+        for target in config.targets.iter() {
+            if target.comm.is_none() && target.cmdline.is_none() && target.cgroup.is_none() {
+                bail!("target {} needs at least one of comm, cmdline or cgroup", target.name);
+            }
+        }
+        Ok(config)
+    }
+}
+
+// compiled form of an AgentTarget, all given matchers have to match.
+pub struct TargetMatcher {
+    pub target: AgentTarget,
+    comm: Option<Regex>,
+    cmdline: Option<Regex>,
+}
+
+impl TargetMatcher {
+    pub fn new(target: AgentTarget) -> Result<TargetMatcher> {
+        let comm = target.comm.as_deref().map(Regex::new).transpose()?;
+        let cmdline = target.cmdline.as_deref().map(Regex::new).transpose()?;
+        Ok(TargetMatcher {
+            target,
+            comm,
+            cmdline,
+        })
+    }
+
+    pub fn matches(&self, process: &ProcessInfo) -> bool {
+        self.comm.as_ref().map_or(true, |x| x.is_match(&process.comm))
+            && self
+                .cmdline
+                .as_ref()
+                .map_or(true, |x| x.is_match(&process.cmdline))
+            && self.target.cgroup.as_ref().map_or(true, |x| {
+                process.cgroups.iter().any(|y| in_cgroup(y, x))
+            })
+    }
+}
+
+// path is cgroup or below it, so /system.slice/api.service doesn't also take
+// /system.slice/api.service-canary along.
+fn in_cgroup(path: &str, cgroup: &str) -> bool {
+    let cgroup = cgroup.trim_end_matches('/');
+    match path.strip_prefix(cgroup) {
+        Some(rest) => rest.is_empty() || rest.starts_with('/'),
+        None => false,
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ProcessInfo {
+    pub pid: u32,
+    pub comm: String,
+    pub cmdline: String,
+    pub cgroups: Vec<String>,
+}
+
+// labels every profile from this host gets w/ --host-labels.
+pub fn host_labels() -> Vec<(String, String)> {
+    [("hostname", "/proc/sys/kernel/hostname"), ("kernel", "/proc/sys/kernel/osrelease")]
+        .iter()
+        .filter_map(|(key, path)| {
+            fs::read_to_string(path)
+                .ok()
+                .map(|x| (key.to_string(), x.trim().to_string()))
+        })
+        .filter(|(_, value)| !value.is_empty())
+        .collect()
+}
+
+// everything currently in /proc. processes that go away mid scan are skipped.
+pub fn list_processes() -> Result<Vec<ProcessInfo>> {
+    let mut out = Vec::new();
+    for entry in fs::read_dir("/proc")? {
+        let entry = entry?;
+        let pid: u32 = match entry.file_name().to_string_lossy().parse() {
+            Ok(x) => x,
+            Err(_) => continue,
+        };
+        let dir = entry.path();
+        let comm = match fs::read_to_string(dir.join("comm")) {
+            Ok(x) => x.trim().to_string(),
+            Err(_) => continue,
+        };
+        let cmdline = fs::read(dir.join("cmdline"))
+            .map(|x| {
+                x.split(|y| *y == 0)
+                    .filter(|y| !y.is_empty())
+                    .map(|y| String::from_utf8_lossy(y).into_owned())
+                    .collect::<Vec<String>>()
+                    .join(" ")
+            })
+            .unwrap_or_default();
+        // lines look like `hierarchy-id:controllers:path`.
+        let cgroups = fs::read_to_string(dir.join("cgroup"))
+            .map(|x| {
+                x.lines()
+                    .filter_map(|y| y.splitn(3, ':').nth(2))
+                    .map(|y| y.to_string())
+                    .collect()
+            })
+            .unwrap_or_default();
+        out.push(ProcessInfo {
+            pid,
+            comm,
+            cmdline,
+            cgroups,
+        });
+    }
+    Ok(out)
+}
+
+// (target, pid) for every running process some target matches. first matching target wins.
+pub fn discover<'a>(matchers: &'a [TargetMatcher]) -> Result<Vec<(&'a AgentTarget, u32)>> {
+    let own_pid = std::process::id();
+    Ok(list_processes()?
+        .iter()
+        .filter(|x| x.pid != own_pid)
+        .filter_map(|x| {
+            matchers
+                .iter()
+                .find(|y| y.matches(x))
+                .map(|y| (&y.target, x.pid))
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cgroups_match_whole_components() {
+        assert!(in_cgroup("/system.slice/api.service", "/system.slice/api.service"));
+        assert!(in_cgroup("/system.slice/api.service/worker", "/system.slice/api.service"));
+        assert!(in_cgroup("/system.slice/api.service", "/system.slice/"));
+        assert!(in_cgroup("/system.slice/api.service", "/"));
+        assert!(!in_cgroup("/system.slice/api.service-canary", "/system.slice/api.service"));
+        assert!(!in_cgroup("/system.slice/api", "/system.slice/api.service"));
+    }
+}
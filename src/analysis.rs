@@ -0,0 +1,608 @@
+use serde_derive::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::str::FromStr;
+
+use crate::defs::{StackNode, StackNodeData};
+
+// self is samples w/ the symbol on top of the stack, total is samples w/ it anywhere on the stack
+// (counted once per sample even if it recurses).
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum Metric {
+    #[serde(rename = "self")]
+    SelfTime,
+    #[serde(rename = "total")]
+    Total,
+}
+
+impl FromStr for Metric {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "self" => Ok(Metric::SelfTime),
+            "total" => Ok(Metric::Total),
+            x => Err(format!("unknown metric {}, expected self or total", x)),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RegressionConfig {
+    pub metric: Metric,
+    // symbols w/ fewer samples than this (on either side, summed over profiles) are skipped.
+    pub min_samples: i64,
+    // in percent of base's share.
+    pub min_pct_change: f64,
+    // one-sided, for the share having gone up.
+    pub max_p_value: f64,
+    // of the interval reported around the change in share.
+    pub confidence: f64,
+}
+
+impl Default for RegressionConfig {
+    fn default() -> Self {
+        RegressionConfig {
+            metric: Metric::Total,
+            min_samples: 20,
+            min_pct_change: 5.0,
+            max_p_value: 0.01,
+            confidence: 0.95,
+        }
+    }
+}
+
+// per symbol (stack_node_data id) sample counts of a single profile.
+#[derive(Debug, Clone, Default)]
+pub struct ProfileCounts {
+    pub samples: i64,
+    pub self_counts: HashMap<i64, i64>,
+    pub total_counts: HashMap<i64, i64>,
+}
+
+impl ProfileCounts {
+    pub fn from_nodes(sn: &[StackNode]) -> ProfileCounts {
+        let (samples, self_counts, total_counts) = count_by(sn, |x| x.stack_node_data_id);
+        ProfileCounts {
+            samples,
+            self_counts,
+            total_counts,
+        }
+    }
+
+    pub fn get(&self, metric: Metric) -> &HashMap<i64, i64> {
+        match metric {
+            Metric::SelfTime => &self.self_counts,
+            Metric::Total => &self.total_counts,
+        }
+    }
+}
+
+// (all samples, self counts, total counts) w/ frames grouped by key. node counts are inclusive
+// along the path, so self is a node minus its children, and total is the count of the outermost
+// node for a key on any path (so recursion isn't counted twice).
+pub fn count_by<K: Hash + Eq + Clone>(
+    sn: &[StackNode],
+    key: impl Fn(&StackNode) -> K,
+) -> (i64, HashMap<K, i64>, HashMap<K, i64>) {
+    let mut children: HashMap<i64, Vec<&StackNode>> = HashMap::new();
+    let mut stack: Vec<(&StackNode, bool)> = Vec::new();
+    for x in sn.iter() {
+        match x.parent_id {
+            Some(p) => children.entry(p).or_default().push(x),
+            None => stack.push((x, true)),
+        }
+    }
+    let samples = stack.iter().map(|(x, _)| x.sample_count).sum();
+    let mut self_counts: HashMap<K, i64> = HashMap::new();
+    let mut total_counts: HashMap<K, i64> = HashMap::new();
+    // keys on the current path, w/ how many times.
+    let mut on_path: HashMap<K, usize> = HashMap::new();
+    while let Some((cur, entering)) = stack.pop() {
+        let k = key(cur);
+        if !entering {
+            *on_path.get_mut(&k).unwrap() -= 1;
+            continue;
+        }
+        let kids = children.get(&cur.id).map(|x| x.as_slice()).unwrap_or_default();
+        let own = cur.sample_count - kids.iter().map(|x| x.sample_count).sum::<i64>();
+        *self_counts.entry(k.clone()).or_default() += own.max(0);
+        let depth = on_path.entry(k.clone()).or_default();
+        if *depth == 0 {
+            *total_counts.entry(k).or_default() += cur.sample_count;
+        }
+        *depth += 1;
+        stack.push((cur, false));
+        stack.extend(kids.iter().map(|x| (*x, true)));
+    }
+    (samples, self_counts, total_counts)
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum GroupBy {
+    Symbol,
+    File,
+    Line,
+}
+
+impl FromStr for GroupBy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "symbol" => Ok(GroupBy::Symbol),
+            "file" => Ok(GroupBy::File),
+            "line" => Ok(GroupBy::Line),
+            x => Err(format!("unknown grouping {}, expected symbol, file or line", x)),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TopEntry {
+    // symbol, file or file:line, depending on the grouping.
+    pub name: String,
+    pub self_samples: i64,
+    pub total_samples: i64,
+    pub self_pct: f64,
+    pub total_pct: f64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TopFunctions {
+    pub samples: i64,
+    pub group_by: GroupBy,
+    pub by_self: Vec<TopEntry>,
+    pub by_total: Vec<TopEntry>,
+}
+
+// the n hottest groups by self and by total samples.
+pub fn top_functions(sn: &[StackNode], snd: &HashMap<i64, StackNodeData>, group_by: GroupBy, n: usize) -> TopFunctions {
+    let name = |sd: &StackNodeData| -> String {
+        let file = sd.file.clone().unwrap_or_else(|| "[unknown]".to_string());
+        match group_by {
+            GroupBy::Symbol => sd.symbol.clone(),
+            GroupBy::File => file,
+            GroupBy::Line => match sd.line_number {
+                Some(x) => format!("{}:{}", file, x),
+                None => file,
+            },
+        }
+    };
+    // frames -> group index, so the traversal only has to hash ints.
+    let mut names: Vec<String> = Vec::new();
+    let mut group_of: HashMap<i64, usize> = HashMap::new();
+    let mut name_idx: HashMap<String, usize> = HashMap::new();
+    for (id, sd) in snd.iter() {
+        let next = names.len();
+        let idx = *name_idx.entry(name(sd)).or_insert_with_key(|x| {
+            names.push(x.clone());
+            next
+        });
+        group_of.insert(*id, idx);
+    }
+    let (samples, self_counts, total_counts) = count_by(sn, |x| group_of.get(&x.stack_node_data_id).copied());
+    let pct = |x: i64| match samples {
+        0 => 0.0,
+        y => x as f64 / y as f64 * 100.0,
+    };
+    let mut entries: Vec<TopEntry> = total_counts
+        .iter()
+        .filter_map(|(k, total)| {
+            let k = (*k)?;
+            let own = self_counts.get(&Some(k)).copied().unwrap_or(0);
+            Some(TopEntry {
+                name: names[k].clone(),
+                self_samples: own,
+                total_samples: *total,
+                self_pct: pct(own),
+                total_pct: pct(*total),
+            })
+        })
+        .collect();
+    entries.sort_by(|a, b| b.self_samples.cmp(&a.self_samples).then_with(|| a.name.cmp(&b.name)));
+    let by_self: Vec<TopEntry> = entries.iter().filter(|x| x.self_samples > 0).take(n).cloned().collect();
+    entries.sort_by(|a, b| b.total_samples.cmp(&a.total_samples).then_with(|| a.name.cmp(&b.name)));
+    entries.truncate(n);
+    TopFunctions {
+        samples,
+        group_by,
+        by_self,
+        by_total: entries,
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Finding {
+    pub stack_node_data_id: i64,
+    pub symbol: String,
+    pub file: Option<String>,
+    pub line_number: Option<i32>,
+    pub base_samples: i64,
+    pub target_samples: i64,
+    // fraction of all samples, averaged over profiles.
+    pub base_share: f64,
+    pub target_share: f64,
+    pub pct_change: f64,
+    // interval around target_share - base_share.
+    pub ci_low: f64,
+    pub ci_high: f64,
+    pub p_value: f64,
+    // welch (profiles as observations) or two-proportion (samples as observations).
+    pub test: String,
+}
+
+// symbols whose share of samples went up from base to target by more than chance would explain.
+// w/ 2+ profiles on both sides, each profile's share is one observation and it's a welch t-test,
+// so run-to-run noise counts. w/ fewer, samples are pooled and it's a two-proportion z-test.
+// only symbols present in base are compared, sorted by the biggest change first.
+pub fn find_regressions(
+    base: &[ProfileCounts],
+    target: &[ProfileCounts],
+    snd: &HashMap<i64, StackNodeData>,
+    config: &RegressionConfig,
+) -> Vec<Finding> {
+    let sum = |profiles: &[ProfileCounts], id: i64| -> i64 {
+        profiles
+            .iter()
+            .map(|x| x.get(config.metric).get(&id).copied().unwrap_or(0))
+            .sum()
+    };
+    let shares = |profiles: &[ProfileCounts], id: i64| -> Vec<f64> {
+        profiles
+            .iter()
+            .filter(|x| x.samples > 0)
+            .map(|x| x.get(config.metric).get(&id).copied().unwrap_or(0) as f64 / x.samples as f64)
+            .collect()
+    };
+    let base_n: i64 = base.iter().map(|x| x.samples).sum();
+    let target_n: i64 = target.iter().map(|x| x.samples).sum();
+    if base_n == 0 || target_n == 0 {
+        return Vec::new();
+    }
+    let mut ids: Vec<i64> = base.iter().flat_map(|x| x.get(config.metric).keys().copied()).collect();
+    ids.sort_unstable();
+    ids.dedup();
+
+    let mut findings: Vec<Finding> = ids
+        .into_iter()
+        .filter_map(|id| {
+            let (base_samples, target_samples) = (sum(base, id), sum(target, id));
+            if base_samples < config.min_samples || target_samples < config.min_samples {
+                return None;
+            }
+            let (base_shares, target_shares) = (shares(base, id), shares(target, id));
+            let welch = match base_shares.len() >= 2 && target_shares.len() >= 2 {
+                true => welch_t_test(&base_shares, &target_shares, config.confidence),
+                false => None,
+            };
+            let (base_share, target_share, test, (p_value, ci_low, ci_high)) = match welch {
+                Some(x) => (mean(&base_shares), mean(&target_shares), "welch", x),
+                None => (
+                    base_samples as f64 / base_n as f64,
+                    target_samples as f64 / target_n as f64,
+                    "two-proportion",
+                    two_proportion_z_test(base_samples, base_n, target_samples, target_n, config.confidence),
+                ),
+            };
+            if base_share <= 0.0 {
+                return None;
+            }
+            let pct_change = (target_share - base_share) / base_share * 100.0;
+            if pct_change < config.min_pct_change || p_value > config.max_p_value {
+                return None;
+            }
+            let sd = snd.get(&id)?;
+            Some(Finding {
+                stack_node_data_id: id,
+                symbol: sd.symbol.clone(),
+                file: sd.file.clone(),
+                line_number: sd.line_number,
+                base_samples,
+                target_samples,
+                base_share,
+                target_share,
+                pct_change,
+                ci_low,
+                ci_high,
+                p_value,
+                test: test.to_string(),
+            })
+        })
+        .collect();
+    findings.sort_by(|a, b| b.pct_change.total_cmp(&a.pct_change));
+    findings
+}
+
+fn mean(x: &[f64]) -> f64 {
+    x.iter().sum::<f64>() / x.len() as f64
+}
+
+fn variance(x: &[f64]) -> f64 {
+    let m = mean(x);
+    x.iter().map(|y| (y - m).powi(2)).sum::<f64>() / (x.len() - 1) as f64
+}
+
+// (one-sided p for b > a, ci low, ci high) on mean(b) - mean(a). none if there's no variance to
+// go off of, e.g. every profile has the exact same share.
+fn welch_t_test(a: &[f64], b: &[f64], confidence: f64) -> Option<(f64, f64, f64)> {
+    let (va, vb) = (variance(a) / a.len() as f64, variance(b) / b.len() as f64);
+    let se = (va + vb).sqrt();
+    if se == 0.0 || !se.is_finite() {
+        return None;
+    }
+    let df = (va + vb).powi(2)
+        / (va.powi(2) / (a.len() - 1) as f64 + vb.powi(2) / (b.len() - 1) as f64);
+    let diff = mean(b) - mean(a);
+    let t = diff / se;
+    let crit = quantile(|x| t_cdf(x, df), 0.5 + confidence / 2.0);
+    Some((1.0 - t_cdf(t, df), diff - crit * se, diff + crit * se))
+}
+
+// same, but w/ every sample as a bernoulli trial of being in the symbol.
+fn two_proportion_z_test(xa: i64, na: i64, xb: i64, nb: i64, confidence: f64) -> (f64, f64, f64) {
+    let (pa, pb) = (xa as f64 / na as f64, xb as f64 / nb as f64);
+    let (na, nb) = (na as f64, nb as f64);
+    let pooled = (xa + xb) as f64 / (na + nb);
+    let se0 = (pooled * (1.0 - pooled) * (1.0 / na + 1.0 / nb)).sqrt();
+    let se = (pa * (1.0 - pa) / na + pb * (1.0 - pb) / nb).sqrt();
+    let diff = pb - pa;
+    let p = match se0 > 0.0 {
+        true => 1.0 - normal_cdf(diff / se0),
+        false => 1.0,
+    };
+    let crit = quantile(normal_cdf, 0.5 + confidence / 2.0);
+    (p, diff - crit * se, diff + crit * se)
+}
+
+pub fn normal_cdf(x: f64) -> f64 {
+    0.5 * erfc(-x / std::f64::consts::SQRT_2)
+}
+
+// chebyshev fit from numerical recipes, good to ~1e-7 which is plenty here.
+fn erfc(x: f64) -> f64 {
+    let z = x.abs();
+    let t = 1.0 / (1.0 + 0.5 * z);
+    let r = t * (-z * z - 1.26551223
+        + t * (1.00002368
+            + t * (0.37409196
+                + t * (0.09678418
+                    + t * (-0.18628806
+                        + t * (0.27886807
+                            + t * (-1.13520398
+                                + t * (1.48851587 + t * (-0.82215223 + t * 0.17087277)))))))))
+        .exp();
+    match x >= 0.0 {
+        true => r,
+        false => 2.0 - r,
+    }
+}
+
+pub fn t_cdf(t: f64, df: f64) -> f64 {
+    let tail = 0.5 * incomplete_beta(df / 2.0, 0.5, df / (df + t * t));
+    match t >= 0.0 {
+        true => 1.0 - tail,
+        false => tail,
+    }
+}
+
+// x w/ cdf(x) = p, by bisection. cdfs here are symmetric around 0 and p > 0.5.
+fn quantile(cdf: impl Fn(f64) -> f64, p: f64) -> f64 {
+    let mut hi = 1.0;
+    while cdf(hi) < p && hi < 1e12 {
+        hi *= 2.0;
+    }
+    let mut lo = 0.0;
+    for _ in 0..100 {
+        let mid = (lo + hi) / 2.0;
+        match cdf(mid) < p {
+            true => lo = mid,
+            false => hi = mid,
+        }
+    }
+    (lo + hi) / 2.0
+}
+
+// regularized incomplete beta I_x(a, b), continued fraction from numerical recipes.
+fn incomplete_beta(a: f64, b: f64, x: f64) -> f64 {
+    if x <= 0.0 {
+        return 0.0;
+    }
+    if x >= 1.0 {
+        return 1.0;
+    }
+    let front = (ln_gamma(a + b) - ln_gamma(a) - ln_gamma(b) + a * x.ln() + b * (1.0 - x).ln()).exp();
+    match x < (a + 1.0) / (a + b + 2.0) {
+        true => front * beta_cf(a, b, x) / a,
+        false => 1.0 - front * beta_cf(b, a, 1.0 - x) / b,
+    }
+}
+
+fn beta_cf(a: f64, b: f64, x: f64) -> f64 {
+    let tiny = 1e-300;
+    let mut c = 1.0;
+    let mut d = 1.0 - (a + b) * x / (a + 1.0);
+    if d.abs() < tiny {
+        d = tiny;
+    }
+    d = 1.0 / d;
+    let mut h = d;
+    for m in 1..300 {
+        let m = m as f64;
+        let m2 = 2.0 * m;
+        let aa = m * (b - m) * x / ((a + m2 - 1.0) * (a + m2));
+        d = 1.0 + aa * d;
+        d = if d.abs() < tiny { tiny } else { d };
+        c = 1.0 + aa / c;
+        c = if c.abs() < tiny { tiny } else { c };
+        d = 1.0 / d;
+        h *= d * c;
+        let aa = -(a + m) * (a + b + m) * x / ((a + m2) * (a + m2 + 1.0));
+        d = 1.0 + aa * d;
+        d = if d.abs() < tiny { tiny } else { d };
+        c = 1.0 + aa / c;
+        c = if c.abs() < tiny { tiny } else { c };
+        d = 1.0 / d;
+        let del = d * c;
+        h *= del;
+        if (del - 1.0).abs() < 1e-12 {
+            break;
+        }
+    }
+    h
+}
+
+// lanczos approximation.
+fn ln_gamma(x: f64) -> f64 {
+    let coef = [
+        76.18009172947146,
+        -86.50532032941677,
+        24.01409824083091,
+        -1.231739572450155,
+        0.1208650973866179e-2,
+        -0.5395239384953e-5,
+    ];
+    let tmp = x + 5.5;
+    let tmp = tmp - (x + 0.5) * tmp.ln();
+    let mut ser = 1.000000000190015;
+    for (i, c) in coef.iter().enumerate() {
+        ser += c / (x + 1.0 + i as f64);
+    }
+    -tmp + (2.5066282746310005 * ser / x).ln()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // reference values are from mpmath.
+    fn close(x: f64, expected: f64, tolerance: f64) {
+        assert!((x - expected).abs() < tolerance, "{} != {}", x, expected);
+    }
+
+    #[test]
+    fn erfc_matches_reference() {
+        close(erfc(0.0), 1.0, 1e-7);
+        close(erfc(0.5), 0.4795001221869535, 1e-7);
+        close(erfc(1.0), 0.15729920705028513, 1e-7);
+        close(erfc(-1.0), 1.842700792949715, 1e-7);
+        close(erfc(2.0), 0.004677734981047266, 1e-7);
+        close(normal_cdf(1.959963984540054), 0.975, 1e-7);
+    }
+
+    #[test]
+    fn ln_gamma_matches_reference() {
+        close(ln_gamma(1.0), 0.0, 1e-9);
+        close(ln_gamma(0.5), 0.5723649429247001, 1e-9);
+        close(ln_gamma(3.7), 1.428072326665388, 1e-9);
+        // 9!
+        close(ln_gamma(10.0), 12.801827480081469, 1e-9);
+    }
+
+    #[test]
+    fn incomplete_beta_matches_reference() {
+        assert_eq!(incomplete_beta(2.0, 3.0, 0.0), 0.0);
+        assert_eq!(incomplete_beta(2.0, 3.0, 1.0), 1.0);
+        close(incomplete_beta(1.0, 1.0, 0.25), 0.25, 1e-9);
+        close(incomplete_beta(2.0, 3.0, 0.3), 0.3483, 1e-9);
+        close(incomplete_beta(4.0, 4.0, 0.5), 0.5, 1e-9);
+        // both sides of the continued fraction switch.
+        close(incomplete_beta(5.0, 0.5, 0.9), 0.3166429150200123, 1e-9);
+        close(incomplete_beta(0.5, 5.0, 0.1), 0.6833570849799878, 1e-9);
+    }
+
+    #[test]
+    fn t_cdf_matches_reference() {
+        close(t_cdf(0.0, 5.0), 0.5, 1e-9);
+        close(t_cdf(2.0, 10.0), 0.9633059826146298, 1e-8);
+        close(t_cdf(-2.0, 10.0), 0.03669401738537019, 1e-8);
+        close(t_cdf(1.5, 3.5), 0.8910909064923274, 1e-8);
+        // cauchy.
+        close(t_cdf(3.0, 1.0), 0.8975836176504333, 1e-8);
+        close(quantile(|x| t_cdf(x, 10.0), 0.975), 2.228138851986274, 1e-6);
+    }
+
+    #[test]
+    fn welch_t_test_matches_textbook() {
+        // example 1 of the welch's t-test article on wikipedia, t = 2.46 w/ df = 24.99.
+        let a = [27.5, 21.0, 19.0, 23.6, 17.0, 17.9, 16.9, 20.1, 21.9, 22.6, 23.1, 19.6, 19.0, 21.7, 21.4];
+        let b = [27.1, 22.0, 20.8, 23.4, 23.4, 23.5, 25.8, 22.0, 24.8, 20.2, 21.9, 22.1, 22.9, 20.5, 24.4];
+        let (p, low, high) = welch_t_test(&a, &b, 0.95).unwrap();
+        close(p, 0.010689000731433421, 1e-6);
+        close(low, 0.3492370661924088, 1e-5);
+        close(high, 3.984096267140932, 1e-5);
+        // and the other way around it's not a regression at all.
+        let (p, _, _) = welch_t_test(&b, &a, 0.95).unwrap();
+        close(p, 1.0 - 0.010689000731433421, 1e-6);
+        assert!(welch_t_test(&[0.1, 0.1], &[0.2, 0.2], 0.95).is_none());
+    }
+
+    #[test]
+    fn two_proportion_z_test_matches_reference() {
+        let (p, low, high) = two_proportion_z_test(30, 1000, 50, 1000, 0.95);
+        close(p, 0.01123943668306264, 1e-6);
+        close(low, 0.00284611572660087, 1e-6);
+        close(high, 0.03715388427339913, 1e-6);
+        // nothing in either, nothing to go off of.
+        assert_eq!(two_proportion_z_test(0, 1000, 0, 1000, 0.95).0, 1.0);
+    }
+
+    // (symbol id, total samples) on top of `samples` per profile.
+    fn profile(samples: i64, counts: &[(i64, i64)]) -> ProfileCounts {
+        let counts: HashMap<i64, i64> = counts.iter().copied().collect();
+        ProfileCounts {
+            samples,
+            self_counts: counts.clone(),
+            total_counts: counts,
+        }
+    }
+
+    fn snd() -> HashMap<i64, StackNodeData> {
+        [(1, "hot"), (2, "steady"), (3, "rare")]
+            .into_iter()
+            .map(|(id, symbol)| {
+                let sd = StackNodeData {
+                    id,
+                    symbol: symbol.to_string(),
+                    file: None,
+                    line_number: None,
+                };
+                (id, sd)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn find_regressions_finds_the_obvious_one() {
+        // hot doubles its share, steady stays put, rare is under min_samples.
+        let base = [
+            profile(1000, &[(1, 98), (2, 298), (3, 3)]),
+            profile(1000, &[(1, 102), (2, 303), (3, 2)]),
+            profile(1000, &[(1, 100), (2, 301), (3, 1)]),
+        ];
+        let target = [
+            profile(1000, &[(1, 198), (2, 300), (3, 9)]),
+            profile(1000, &[(1, 205), (2, 299), (3, 8)]),
+            profile(1000, &[(1, 201), (2, 302), (3, 9)]),
+        ];
+        let config = RegressionConfig::default();
+        let findings = find_regressions(&base, &target, &snd(), &config);
+        assert_eq!(findings.len(), 1, "{:?}", findings);
+        let x = &findings[0];
+        assert_eq!((x.symbol.as_str(), x.test.as_str()), ("hot", "welch"));
+        assert_eq!((x.base_samples, x.target_samples), (300, 604));
+        close(x.pct_change, 101.333, 1e-2);
+        assert!(x.p_value < 1e-4, "{}", x.p_value);
+        assert!(x.ci_low > 0.0 && x.ci_low < x.target_share - x.base_share && x.ci_high > x.target_share - x.base_share);
+
+        // one profile a side pools the samples instead.
+        let findings = find_regressions(&base[..1], &target[..1], &snd(), &config);
+        assert_eq!(findings.len(), 1, "{:?}", findings);
+        assert_eq!((findings[0].symbol.as_str(), findings[0].test.as_str()), ("hot", "two-proportion"));
+
+        // going down isn't a regression.
+        assert!(find_regressions(&target, &base, &snd(), &config).is_empty());
+        assert!(find_regressions(&base, &[], &snd(), &config).is_empty());
+    }
+}
@@ -1,4 +1,4 @@
-use anyhow::{bail, Result};
+use anyhow::{anyhow, bail, Result};
 use atomic_counter::AtomicCounter;
 use blazesym::{BlazeSymbolizer, SymbolSrcCfg, SymbolizedResult, SymbolizerFeature};
 
@@ -7,31 +7,38 @@ use core::time::Duration;
 use deadqueue::limited::Queue;
 use deepsize::DeepSizeOf;
 
-use highway::{HighwayHash, HighwayHasher};
 use libbpf_rs::RingBufferBuilder;
 use perf_event_open_sys as perf;
 use std::cmp::min;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::default::Default;
 use std::future::Future;
-use std::process::{Child, Command};
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::process::Child;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::mpsc::{channel, Sender, sync_channel, SyncSender};
-use std::sync::Arc;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::sync::{Arc, Mutex};
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 use std::{process, thread, time};
 use dotenvy::dotenv;
+use reqwest::header::{CONTENT_ENCODING, CONTENT_TYPE};
 use sto::bpftune::bpftune_bss_types::stacktrace_event;
+use chrono::Utc;
+use sto::agent::{discover, host_labels, AgentConfig, TargetMatcher};
 use sto::defs::{
-    Args, EventType, ProcessQueue, Executable, ReadQueue, StackInfo, StackNode, StackNodeData,
-    StoData, HASHER_SEED, PROCESS_TASK_COUNT, READ_TASK_COUNT, WORKER_COUNT,
+    Args, Command, EventType, ImportKind, ProcessQueue, OutputFormat, ReadQueue, ServerInfo, StackInfo,
+    StoData, StreamAck, PROCESS_TASK_COUNT,
+    PROTOCOL_VERSION, READ_TASK_COUNT, STREAM_ACK_EVERY, WORKER_COUNT,
 };
+use sto::export::{chrome_trace, folded, speedscope};
+use sto::import::{to_sto_data, Frame, ImportFormat, ProfileInfo};
+use sto::wire::{self, WireCompression, WireFormat};
 extern crate clap;
 extern crate num_cpus;
 use libbpf_rs::libbpf_sys::pid_t;
 use tracing::{event, span, Level};
-use moka::sync::Cache;
-use once_cell::sync::Lazy;
+use once_cell::sync::{Lazy, OnceCell};
 use perf::perf_event_open;
 
 use rlimit::Resource;
@@ -39,41 +46,165 @@ use rlimit::Resource;
 use rocket::form::validate::Len;
 
 use sto::bpftune::*;
-use symbolic_demangle::{Demangle, DemangleOptions};
 use tracing_subscriber::Layer;
 
-static SYM_CACHE: Lazy<Cache<String, String, ahash::RandomState>> = Lazy::new(|| {
-    Cache::builder()
-        .weigher(|key: &String, _value: &String| -> u32 {
-            key.len().try_into().unwrap_or(u32::MAX)
-        })
-        .max_capacity(32 * 1024 * 1024)
-        .build_with_hasher(ahash::RandomState::default())
-});
-
-static DATA_ID_CACHE: Lazy<Cache<StackNodeData, i64, ahash::RandomState>> = Lazy::new(|| {
-    Cache::builder()
-        .weigher(|key: &StackNodeData, _value: &i64| -> u32 {
-            key.symbol.len().try_into().unwrap_or(u32::MAX)
-                + key.file.len().try_into().unwrap_or(u32::MAX)
-        })
-        .max_capacity(32 * 1024 * 1024)
-        .build_with_hasher(ahash::RandomState::default())
-});
+static SERVER_INFO: OnceCell<Option<ServerInfo>> = OnceCell::new();
 
-static NODE_ID_CACHE: Lazy<Cache<StackNode, i64, ahash::RandomState>> = Lazy::new(|| {
-    Cache::builder()
-        .max_capacity(8 * 1024 * 1024)
-        .build_with_hasher(ahash::RandomState::default())
-});
+// set on ctrl-c. profiling stops at its next poll and whatever was collected still gets sunk.
+static STOP: AtomicBool = AtomicBool::new(false);
 
-static MISC_ID_CACHE: Lazy<Cache<String, i64, ahash::RandomState>> = Lazy::new(|| {
-    Cache::builder()
-        .weigher(|key: &String, _value: &i64| -> u32 { key.len().try_into().unwrap_or(u32::MAX) })
-        .max_capacity(2 * 1024 * 1024)
-        .build_with_hasher(ahash::RandomState::default())
-});
+// a second ctrl-c quits right away.
+fn stop_on_ctrlc() {
+    thread::spawn(|| {
+        let ctrlc = match async_ctrlc::CtrlC::new() {
+            Ok(x) => x,
+            Err(x) => {
+                event!(Level::WARN, "unable to catch ctrl-c, output may be lost on exit: {}", x);
+                return;
+            }
+        };
+        let mut ctrlc = Box::pin(ctrlc);
+        futures::executor::block_on(ctrlc.as_mut());
+        event!(Level::INFO, "stopping, ctrl-c again to quit w/o writing anything out");
+        STOP.store(true, Ordering::SeqCst);
+        futures::executor::block_on(ctrlc.as_mut());
+        process::exit(130);
+    });
+}
 
+// asks the server what it speaks, once. None means it predates /api/version, so plain json only.
+fn server_info(url: &str) -> Option<ServerInfo> {
+    SERVER_INFO
+        .get_or_init(|| {
+            let version_url = match reqwest::Url::parse(url).and_then(|x| x.join("/api/version")) {
+                Ok(x) => x,
+                Err(x) => {
+                    event!(Level::ERROR, "bad server url {}: {}", url, x);
+                    return None;
+                }
+            };
+            match reqwest::blocking::get(version_url)
+                .and_then(|x| x.error_for_status())
+                .and_then(|x| x.json::<ServerInfo>())
+            {
+                Ok(x) => {
+                    event!(Level::DEBUG, "server capabilities: {:?}", x);
+                    Some(x)
+                }
+                Err(x) => {
+                    event!(Level::WARN, "unable to get server capabilities, assuming a legacy server: {}", x);
+                    None
+                }
+            }
+        })
+        .clone()
+}
+
+static STREAM_TX: OnceCell<Sender<Vec<u8>>> = OnceCell::new();
+
+// batches for --output other than server pile up here until profiling is done.
+static OUTPUT: Lazy<Mutex<StoData>> = Lazy::new(|| Mutex::new(StoData::default()));
+
+// a single thread owns the ingest websocket and feeds it encoded frames, reconnecting whenever
+// it drops. every STREAM_ACK_EVERY frames it waits on the server's ack, so at most that many
+// frames are ever unconfirmed. those are kept until acked, and if the stream fails they're
+// posted to url instead. a connection that drops w/o an ack leaves no way to tell what the
+// server got, so those may be counted twice rather than lost.
+fn stream_sender(url: &str, format: WireFormat, compression: WireCompression) -> Sender<Vec<u8>> {
+    STREAM_TX
+        .get_or_init(|| {
+            let (tx, rx) = channel::<Vec<u8>>();
+            let post_url = url.to_string();
+            let mut stream_url = reqwest::Url::parse(url)
+                .and_then(|x| x.join("/api/v1/data/stream"))
+                .expect("bad server url");
+            let scheme = match stream_url.scheme() {
+                "https" => "wss",
+                _ => "ws",
+            };
+            stream_url.set_scheme(scheme).expect("bad server url");
+            stream_url
+                .query_pairs_mut()
+                .append_pair("format", &format.to_string().to_lowercase())
+                .append_pair("compression", &compression.to_string().to_lowercase())
+                .append_pair("ack_every", &STREAM_ACK_EVERY.to_string());
+            thread::spawn(move || {
+                let fall_back = |frames: Vec<Vec<u8>>| {
+                    for frame in frames {
+                        if let Err(x) = post_data(&post_url, frame, format, compression) {
+                            event!(Level::ERROR, "dropping frame: {}", x);
+                        }
+                    }
+                };
+                let mut socket = None;
+                let mut unacked: Vec<Vec<u8>> = Vec::new();
+                // frames the server has acked on this connection, its acks count from the start.
+                let mut acked: u64 = 0;
+                for frame in rx {
+                    if socket.is_none() {
+                        match tungstenite::connect(stream_url.as_str()) {
+                            Ok((x, _)) => {
+                                event!(Level::INFO, "opened ingest stream to {}", stream_url);
+                                socket = Some(x);
+                                acked = 0;
+                            }
+                            Err(x) => {
+                                event!(Level::ERROR, "unable to open ingest stream, posting frame instead: {}", x);
+                                fall_back(vec![frame]);
+                                continue;
+                            }
+                        }
+                    }
+                    let ws = socket.as_mut().unwrap();
+                    unacked.push(frame.clone());
+                    if let Err(x) = ws.send(tungstenite::Message::Binary(frame.into())) {
+                        event!(Level::ERROR, "failed to stream data, posting {} unacked frames instead: {}", unacked.len(), x);
+                        fall_back(std::mem::take(&mut unacked));
+                        socket = None;
+                        continue;
+                    }
+                    if (unacked.len() as u64) < STREAM_ACK_EVERY {
+                        continue;
+                    }
+                    let ack = loop {
+                        match ws.read() {
+                            Ok(tungstenite::Message::Text(x)) => {
+                                break serde_json::from_str::<StreamAck>(x.as_str())
+                                    .map_err(|x| x.to_string())
+                            }
+                            Ok(tungstenite::Message::Close(_)) => {
+                                break Err("server closed the stream".to_string())
+                            }
+                            Ok(_) => continue,
+                            Err(x) => break Err(x.to_string()),
+                        }
+                    };
+                    match ack {
+                        Ok(StreamAck { error: None, frames, samples }) => {
+                            event!(Level::DEBUG, "server acked {} frames, {} samples", frames, samples);
+                            acked = frames;
+                            unacked.clear();
+                        }
+                        Ok(StreamAck { error: Some(x), frames, .. }) => {
+                            // the server stored everything up to the frame it failed on.
+                            let stored = (frames.saturating_sub(acked) as usize).min(unacked.len());
+                            unacked.drain(..stored);
+                            event!(Level::ERROR, "ingest stream failed, posting {} unacked frames instead: {}", unacked.len(), x);
+                            fall_back(std::mem::take(&mut unacked));
+                            socket = None;
+                        }
+                        Err(x) => {
+                            event!(Level::ERROR, "ingest stream failed, posting {} unacked frames instead: {}", unacked.len(), x);
+                            fall_back(std::mem::take(&mut unacked));
+                            socket = None;
+                        }
+                    }
+                }
+            });
+            tx
+        })
+        .clone()
+}
 
 fn bump_memlock_rlimit() -> Result<()> {
     let (ml_soft, ml_hard) = Resource::get(rlimit::Resource::MEMLOCK)?;
@@ -97,7 +228,8 @@ fn bump_memlock_rlimit() -> Result<()> {
     Ok(())
 }
 
-fn profile(args: Args, tx: Sender<StackInfo>) -> Result<()> {
+// profiles until `until` passes or ctrl-c, whichever comes first.
+fn profile(args: Args, tx: Sender<StackInfo>, until: Option<Instant>) -> Result<()> {
     event!(Level::DEBUG,"IN PROFILE");
     let skel_builder = BpftuneSkelBuilder::default();
     bump_memlock_rlimit()?;
@@ -163,80 +295,36 @@ fn profile(args: Args, tx: Sender<StackInfo>) -> Result<()> {
 
     loop {
         rb.poll(Duration::from_millis(1))?;
-        thread::sleep(Duration::from_secs(5));
-    }
-
-    event!(Level::DEBUG,"DONE ONE RUN");
-    perf_fds.capacity();
-    Ok(())
-}
-
-fn cached_demangle(mangled: &str) -> String {
-    match SYM_CACHE.get(mangled) {
-        Some(hit) => hit,
-        None => {
-            let name = symbolic_common::Name::from(mangled);
-            let demangled = name.try_demangle(DemangleOptions::name_only());
-            SYM_CACHE.insert(mangled.into(), demangled.clone().into());
-            demangled.into()
+        if STOP.load(Ordering::SeqCst) {
+            break;
         }
-    }
-}
-
-fn misc_id(data: String) -> i64 {
-    match MISC_ID_CACHE.get(&data) {
-        Some(x) => x,
-        None => {
-            let mut hasher = HighwayHasher::new(HASHER_SEED);
-            hasher.append(data.as_bytes());
-            let id_neg: i64 = hasher.finalize64() as i64;
-            let id = id_neg.abs() as i64;
-            MISC_ID_CACHE.insert(data.clone(), id);
-            id
-        }
-    }
-}
-
-fn id_stack_node(data: &mut StackNode) {
-    let id = match NODE_ID_CACHE.get(data) {
-        Some(hit) => hit,
-        None => {
-            let mut hasher = HighwayHasher::new(HASHER_SEED);
-            if let Some(parent_id) = data.parent_id {
-                hasher.append(&parent_id.to_be_bytes());
+        let pause = match until {
+            Some(x) => {
+                let now = Instant::now();
+                if now >= x {
+                    break;
+                }
+                min(x - now, Duration::from_secs(5))
             }
-            hasher.append(&data.stack_node_data_id.to_be_bytes());
-            hasher.append(&data.executable_id.to_be_bytes());
-            let id_neg: i64 = hasher.finalize64() as i64;
-            let id = id_neg.abs() as i64;
-            // should probably restructure this a bit because of 0 id in cache.
-            NODE_ID_CACHE.insert(data.clone(), id);
-            id
+            None => Duration::from_secs(5),
+        };
+        // in small steps so ctrl-c doesn't wait out the whole pause.
+        let paused = Instant::now();
+        while paused.elapsed() < pause && !STOP.load(Ordering::SeqCst) {
+            thread::sleep(min(pause - paused.elapsed(), Duration::from_millis(100)));
         }
-    };
-    data.id = id;
-}
+    }
+    rb.poll(Duration::from_millis(1))?;
 
-fn id_data(data: &mut StackNodeData) {
-    let id = match DATA_ID_CACHE.get(data) {
-        Some(hit) => hit,
-        None => {
-            let mut hasher = HighwayHasher::new(HASHER_SEED);
-            hasher.append(data.symbol.as_bytes());
-            if let Some(file) = data.clone().file {
-                hasher.append(file.as_bytes());
-            }
-            if let Some(line_number) = data.line_number {
-                hasher.append(&line_number.to_be_bytes());
-            }
-            let id_neg: i64 = hasher.finalize64() as i64;
-            let id = id_neg.abs() as i64;
-            // should probably restructure this a bit because of 0 id in cache.
-            DATA_ID_CACHE.insert(data.clone(), id);
-            id
+    event!(Level::DEBUG,"DONE ONE RUN");
+    // detach before closing the perf events, the agent does this once per window.
+    for (fd, link) in perf_fds.drain() {
+        drop(link);
+        unsafe {
+            libc::close(fd);
         }
-    };
-    data.id = id;
+    }
+    Ok(())
 }
 
 fn symbolize(stack_info: StackInfo) -> Vec<Vec<SymbolizedResult>> {
@@ -249,148 +337,200 @@ fn symbolize(stack_info: StackInfo) -> Vec<Vec<SymbolizedResult>> {
     symlist
 }
 
-fn process(args: Args) -> Result<(), anyhow::Error> {
+// profiles args.pid until `until` (or ctrl-c), symbolizing as samples come in and sinking
+// them in batches. returns once everything collected has been sunk.
+fn process(args: Args, until: Option<Instant>) -> Result<(), anyhow::Error> {
     event!(Level::DEBUG,"IN PROCESS");
     let (tx, rx) = channel();
     let i_args = args.clone();
     let b_args = args.clone();
-    thread::spawn(move || {
+    let collector = thread::spawn(move || {
         let ii_args = i_args.clone();
         let mut buf = Vec::new();
-        loop {
-            let iii_args = ii_args.clone();
-            match rx.recv() {
-                Ok(data_chunk) => {
-                    event!(Level::DEBUG,"READ DATA, BUF LEN:{}", buf.len().clone());
-                    if(buf.len() < 200){
-                        buf.push(symbolize(data_chunk).to_owned());
-                    } else {
-                        let old_buf = buf.to_owned();
-                        thread::spawn(move || {
-                            let iiii_args = iii_args.clone();
-                            process_and_sink_data(old_buf.clone(), iiii_args.clone());
-                            event!(Level::INFO,"SANK DATA");
-                        });
-                        buf.clear();
+        let mut sinks = Vec::new();
+        // errors once every sender is gone, i.e. profiling stopped.
+        while let Ok(data_chunk) = rx.recv() {
+            event!(Level::DEBUG,"READ DATA, BUF LEN:{}", buf.len().clone());
+            buf.push(symbolize(data_chunk).to_owned());
+            if buf.len() >= 200 {
+                let old_buf = std::mem::take(&mut buf);
+                let iii_args = ii_args.clone();
+                sinks.push(thread::spawn(move || {
+                    match process_and_sink_data(old_buf, iii_args) {
+                        Ok(_) => event!(Level::INFO,"SANK DATA"),
+                        Err(x) => event!(Level::ERROR, "failed to sink data: {}", x),
                     }
-
-                }
-                Err(_) => {
-                }
+                }));
+            }
+        }
+        if !buf.is_empty() {
+            if let Err(x) = process_and_sink_data(buf, ii_args.clone()) {
+                event!(Level::ERROR, "failed to sink data: {}", x);
             }
         }
+        for sink in sinks {
+            let _ = sink.join();
+        }
     });
 
-
-    profile(b_args.clone(), tx).expect("TODO: panic message");
+    profile(b_args.clone(), tx, until)?;
+    collector.join().expect("collector thread panicked");
 
     // done.
     Ok(())
 }
 
+// every interval, profile everything the config matches for one window, one thread per
+// process, and upload each as a profile stamped w/ the window start.
+fn agent(args: Args, config: AgentConfig) -> Result<(), anyhow::Error> {
+    let matchers = config
+        .targets
+        .iter()
+        .cloned()
+        .map(TargetMatcher::new)
+        .collect::<Result<Vec<TargetMatcher>>>()?;
+    let interval = Duration::from_secs(config.interval_secs);
+    let window = Duration::from_secs(config.window_secs);
+    loop {
+        let started = Instant::now();
+        let window_start = Utc::now();
+        let found = discover(&matchers)?;
+        event!(Level::INFO, "agent window at {}, {} matching processes", window_start, found.len());
+        let workers: Vec<_> = found
+            .into_iter()
+            .map(|(target, pid)| {
+                let mut target_args = args.clone();
+                target_args.command = None;
+                target_args.pid = pid;
+                target_args.binary = Some(target.name.clone());
+                target_args.version = target.version.clone();
+                target_args.window_start = Some(window_start);
+                target_args.output = OutputFormat::Server;
+                if let Some(x) = config.url.as_ref() {
+                    target_args.url = x.clone();
+                }
+                if let Some(x) = config.sample_freq {
+                    target_args.sample_freq = x;
+                }
+                if let Some(x) = config.event_type {
+                    target_args.event_type = x;
+                }
+                let mut labels: BTreeMap<String, String> = target_args.labels.drain(..).collect();
+                labels.extend(config.labels.clone());
+                labels.extend(target.labels.clone());
+                target_args.labels = labels.into_iter().collect();
+                thread::spawn(move || {
+                    if let Err(x) = process(target_args, Some(started + window)) {
+                        event!(Level::ERROR, "failed to profile pid {}: {}", pid, x);
+                    }
+                })
+            })
+            .collect();
+        for worker in workers {
+            let _ = worker.join();
+        }
+        let elapsed = started.elapsed();
+        if elapsed < interval {
+            thread::sleep(interval - elapsed);
+        }
+    }
+}
+
 fn process_and_sink_data(
-    mut symlists: Vec<Vec<Vec<SymbolizedResult>>>,
+    symlists: Vec<Vec<Vec<SymbolizedResult>>>,
     args: Args,
 ) -> Result<(), anyhow::Error> {
-        event!(Level::DEBUG,"stack is");
-        let mut stack_node_map: HashMap<i64, StackNode> = HashMap::new();
-        let mut stack_node_data_map: HashMap<i64, StackNodeData> = HashMap::new();
-        let mut executable_map: HashMap<i64, Executable> = HashMap::new();
-        let basename = args.clone().binary.clone();
-        let version = args.clone().version;
-        let id = match version {
-            Some(x) => {
-                misc_id(format!("{}{}", args.clone().binary.unwrap().clone(), x))
-            },
-            None => {
-                misc_id(args.clone().binary.unwrap().clone())
-            }
-        };
+    event!(Level::DEBUG,"stack is");
+    let info = ProfileInfo {
+        basename: args.binary.clone().unwrap(),
+        version: args.version.clone(),
+        event: args.event_type.to_string(),
+        labels: args.labels.iter().cloned().collect(),
+        timestamp: args.window_start,
+    };
+    let raw_size: i64 = symlists.iter().map(|x| x.deep_size_of() as i64).sum();
+    // one per address, which is what sample_count has always counted here. to_sto_data only
+    // sees inlined frames already flattened out.
+    let frame_count: i64 = symlists.iter().map(|x| x.len() as i64).sum();
+    let stacks = symlists.into_iter().map(|mut symlist| {
+        symlist.reverse();
+        let frames = symlist
+            .into_iter()
+            .flatten()
+            .map(|frame| Frame {
+                symbol: frame.symbol,
+                file: if frame.path.trim().is_empty() {
+                    None
+                } else {
+                    Some(frame.path.trim().into())
+                },
+                line_number: if frame.line_no > 0 {
+                    Some(frame.line_no as i32)
+                } else {
+                    None
+                },
+            })
+            .collect();
+        (frames, 1)
+    });
+    let mut data_out = to_sto_data(&info, stacks);
+    for executable in data_out.profiled_binaries.iter_mut() {
+        executable.raw_data_size = raw_size;
+        executable.sample_count = frame_count;
+    }
+    sink_data(data_out, &args)
+}
 
-        let executable = Executable {
-            id,
-            event: args.event_type.to_string(),
-            build_id: args.version,
-            basename: basename.unwrap(),
-            updated_at: None,
-            created_at: None,
-            sample_count: 0,
-            raw_data_size: 0,
-            processed_data_size: 0,
-        };
+// uploads (or stashes for --output) one batch of already converted data.
+fn sink_data(data_out: StoData, args: &Args) -> Result<(), anyhow::Error> {
+    if args.output != OutputFormat::Server {
+        OUTPUT.lock().unwrap().merge(data_out);
+        return Ok(());
+    }
 
-        let _cur_bin_id = executable.id;
-    for mut symlist in symlists {
-        let mut parent_id: Option<i64> = None;
-        symlist.reverse();
-        for mut stack in symlist {
-            executable_map
-                .entry(executable.id)
-                .or_insert(executable.clone());
-            executable_map
-                .entry(executable.id)
-                .and_modify(|e| e.sample_count += 1)
-                .and_modify(|e| e.raw_data_size += stack.deep_size_of() as i64);
-            // stack.reverse();
-            for (_i, frame) in stack.iter().enumerate() {
-                let mut data = StackNodeData {
-                    id: 0,
-                    symbol: cached_demangle(&frame.symbol).to_string(),
-                    file: if frame.path.trim().is_empty() {
-                        None
-                    } else {
-                        Some(frame.path.trim().into())
-                    },
-                    line_number: if frame.line_no > 0 {
-                        Some(frame.line_no as i32)
-                    } else {
-                        None
-                    },
-                };
-                id_data(&mut data);
-                stack_node_data_map.entry(data.id).or_insert(data.clone());
-                let mut stack_node = StackNode {
-                    id: 0,
-                    parent_id,
-                    stack_node_data_id: data.id,
-                    executable_id: executable.id,
-                    sample_count: 1,
-                };
-                id_stack_node(&mut stack_node);
-                stack_node_map
-                    .entry(stack_node.id)
-                    .and_modify(|e| e.sample_count += 1)
-                    .or_insert(stack_node.clone());
-                parent_id = Some(stack_node.id);
+    let (format, compression, streaming) = match server_info(&args.url) {
+        Some(info) => {
+            if !info.supports_protocol(PROTOCOL_VERSION) {
+                bail!(
+                    "server speaks protocol versions {} through {}, this cli speaks {}",
+                    info.min_protocol_version,
+                    info.protocol_version,
+                    PROTOCOL_VERSION
+                );
             }
+            let (format, compression) = info.negotiate(args.format, args.compression);
+            (format, compression, args.stream && info.streaming)
         }
-    }
-
-        let mut data_out = StoData {
-            stack_nodes: stack_node_map.values().map(|x| (*x).clone()).collect(),
-            stack_node_datas: stack_node_data_map.values().map(|x| (*x).clone()).collect(),
-            profiled_binaries: executable_map.values().map(|x| (*x).clone()).collect(),
-        };
+        None => (WireFormat::Json, WireCompression::None, false),
+    };
 
-        executable_map
-            .entry(executable.id)
-            .and_modify(|e| e.processed_data_size += data_out.deep_size_of() as i64);
+    // processed size is what actually goes over the wire, the server fills it in from the body.
+    let body = wire::encode(&data_out, format, compression)?;
+    let raw_size: i64 = data_out.profiled_binaries.iter().map(|x| x.raw_data_size).sum();
+    event!(Level::INFO, "encoded {} bytes of raw stacks into {} bytes of {}/{}", raw_size, body.len(), format, compression);
 
-        data_out.profiled_binaries = executable_map.values().map(|x| (*x).clone()).collect();
+    if streaming {
+        stream_sender(&args.url, format, compression).send(body)?;
+        return Ok(());
+    }
 
-        let client = reqwest::blocking::Client::new();
-        match client.post(args.url.clone()).json(&data_out).send() {
-            Ok(x) => match x.error_for_status() {
-                Ok(_x) => {}
-                Err(x) => {
-                    event!(Level::ERROR,"failed to post data: {}", x);
-                }
-            },
-            Err(x) => {
-                event!(Level::ERROR,"failed to post data: {}", x);
-            }
-        }
+    // callers decide whether a failed batch is fatal, `upload` can't claim a file got stored.
+    post_data(&args.url, body, format, compression)
+}
 
+fn post_data(url: &str, body: Vec<u8>, format: WireFormat, compression: WireCompression) -> Result<()> {
+    let client = reqwest::blocking::Client::new();
+    let mut request = client
+        .post(url)
+        .header(CONTENT_TYPE, format.content_type());
+    if let Some(encoding) = compression.content_encoding() {
+        request = request.header(CONTENT_ENCODING, encoding);
+    }
+    request
+        .body(body)
+        .send()
+        .and_then(|x| x.error_for_status())
+        .map_err(|x| anyhow!("failed to post data: {}", x))?;
     Ok(())
 }
 
@@ -401,7 +541,9 @@ fn main() -> Result<(), anyhow::Error> {
     let console_layer = console_subscriber::spawn();
     tracing_subscriber::registry()
         .with(console_layer)
+        // logs go to stderr so stdout is free for --output.
         .with(tracing_subscriber::fmt::layer()
+            .with_writer(std::io::stderr)
             .with_level(true)
             .with_line_number(true)
             .with_thread_names(true)
@@ -409,6 +551,52 @@ fn main() -> Result<(), anyhow::Error> {
         .init();
 
     let mut args = Args::parse();
+    if args.output == OutputFormat::Server && args.output_file.is_some() {
+        args.output = OutputFormat::Sto;
+    }
+    // the ui lives in the server binary, which gets installed next to this one.
+    if let Some(Command::View { file }) = args.command.clone() {
+        let server = std::env::current_exe()?.with_file_name("server");
+        if !server.is_file() {
+            bail!("view needs the server binary next to this one, there's no {}. install both (e.g. cargo install --path . --bins), or run `server view {}` w/ it from wherever it is.", server.display(), file.display());
+        }
+        let status = process::Command::new(&server)
+            .arg("view")
+            .arg(&file)
+            .status()
+            .map_err(|x| anyhow!("couldn't run {}: {}", server.display(), x))?;
+        if !status.success() {
+            bail!("{} view exited w/ {}", server.display(), status);
+        }
+        return Ok(());
+    }
+    if let Some(Command::Upload { file }) = args.command.clone() {
+        let data: StoData = wire::decode_file(&read_input(&file)?, u64::MAX)?;
+        data.check_version().map_err(|x| anyhow!(x))?;
+        event!(Level::INFO, "uploading {} stack nodes from {}", data.stack_nodes.len(), file.display());
+        args.output = OutputFormat::Server;
+        // a stream is fire and forget, this has to know the server took it before exiting 0.
+        args.stream = false;
+        sink_data(data, &args)?;
+        event!(Level::INFO, "uploaded {}", file.display());
+        return Ok(());
+    }
+    // imports aren't from this host, so no host labels.
+    if let Some(Command::Import { kind }) = args.command.clone() {
+        import(&args, kind)?;
+        return write_output(&args);
+    }
+    // anything given explicitly wins over what's detected.
+    if args.host_labels {
+        let mut labels: BTreeMap<String, String> = host_labels().into_iter().collect();
+        labels.extend(args.labels.drain(..));
+        args.labels = labels.into_iter().collect();
+    }
+    if let Some(Command::Agent { config }) = args.command.clone() {
+        let config = AgentConfig::load(&config)?;
+        bump_memlock_rlimit()?;
+        return agent(args, config);
+    }
     if args.binary.is_none(){
         args.binary = Some("provide_a_meaningful_name".to_string());
     }
@@ -417,8 +605,90 @@ fn main() -> Result<(), anyhow::Error> {
         std::process::exit(-1);
     }
 
+    // a file might not get uploaded for a while, so stamp it w/ when it was actually profiled.
+    if matches!(args.output, OutputFormat::Sto | OutputFormat::Json | OutputFormat::Msgpack) {
+        args.window_start = Some(Utc::now());
+    }
+
+    let until = args.duration.map(|x| Instant::now() + Duration::from_secs(x));
+    stop_on_ctrlc();
+    flush_periodically(args.clone());
+    process(args.clone(), until)?;
+    write_output(&args)?;
 
-    process(args.clone());
+    Ok(())
+}
 
+fn read_input(file: &PathBuf) -> Result<Vec<u8>, anyhow::Error> {
+    if file.as_os_str() == "-" {
+        let mut buf = Vec::new();
+        std::io::stdin().read_to_end(&mut buf)?;
+        return Ok(buf);
+    }
+    Ok(std::fs::read(file)?)
+}
+
+// converts a profile from another tool and sinks it like anything profiled here.
+fn import(args: &Args, kind: ImportKind) -> Result<(), anyhow::Error> {
+    let (format, file, event, label_keys) = match kind {
+        ImportKind::PerfScript { file, event } => (ImportFormat::PerfScript, file, event, vec![]),
+        ImportKind::Pprof { file, sample_type, label_key } => (ImportFormat::Pprof, file, sample_type, label_key),
+        ImportKind::Folded { file, event } => (ImportFormat::Folded, file, Some(event), vec![]),
+    };
+    let input = read_input(&file)?;
+    // it's the user's own file, same as upload there's no limit.
+    let imported = sto::import::import(format, &input, event.as_deref(), &label_keys, u64::MAX)?;
+    if imported.skipped > 0 {
+        event!(Level::WARN, "skipped {} samples of other events or w/o a stack", imported.skipped);
+    }
+    event!(Level::INFO, "read {} samples from {}", imported.samples, file.display());
+    if imported.stacks.is_empty() {
+        bail!("nothing to import in {}", file.display());
+    }
+    let labels: BTreeMap<String, String> = args.labels.iter().cloned().collect();
+    let data_out = imported.into_sto_data(args.binary.clone(), args.version.clone(), &labels, input.len() as i64)?;
+    sink_data(data_out, args)
+}
+
+// how often a long run's --output-file gets rewritten w/ everything so far, so a killed run
+// doesn't lose it all.
+const FLUSH_EVERY: Duration = Duration::from_secs(30);
+
+fn flush_periodically(args: Args) {
+    if args.output == OutputFormat::Server || args.output_file.is_none() {
+        return;
+    }
+    thread::spawn(move || loop {
+        thread::sleep(FLUSH_EVERY);
+        if let Err(x) = write_output(&args) {
+            event!(Level::WARN, "failed to flush output: {}", x);
+        }
+    });
+}
+
+// everything collected so far for --output other than server, written to --output-file or
+// stdout. files are replaced whole (via a rename), so they're never half written.
+fn write_output(args: &Args) -> Result<(), anyhow::Error> {
+    // held throughout so flushes don't interleave.
+    let data = OUTPUT.lock().unwrap();
+    let name = args.binary.clone().unwrap_or_default();
+    let body = match args.output {
+        OutputFormat::Server => return Ok(()),
+        OutputFormat::Folded => folded(&data.stack_nodes, &data.stack_node_datas, args.lines).into_bytes(),
+        OutputFormat::Speedscope => speedscope(&name, &data.stack_nodes, &data.stack_node_datas).into_bytes(),
+        OutputFormat::Chrome => chrome_trace(&name, &data.stack_nodes, &data.stack_node_datas).into_bytes(),
+        OutputFormat::Sto => wire::encode_file(&*data, args.format, args.compression)?,
+        OutputFormat::Json => wire::encode(&*data, WireFormat::Json, WireCompression::None)?,
+        OutputFormat::Msgpack => wire::encode(&*data, WireFormat::Msgpack, WireCompression::None)?,
+    };
+    match args.output_file.as_ref() {
+        Some(x) => {
+            let mut tmp = x.clone().into_os_string();
+            tmp.push(".tmp");
+            std::fs::write(&tmp, body)?;
+            std::fs::rename(&tmp, x)?;
+        }
+        None => std::io::stdout().write_all(&body)?,
+    }
     Ok(())
 }
@@ -1,15 +1,16 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use once_cell::sync::OnceCell;
 use std::borrow::Cow;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 
 use dotenvy::dotenv;
 
 use chrono::format::Numeric::Day;
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, Duration, DurationRound, TimeZone, Utc};
 use reqwest::header::{REFERER, REFRESH};
 use rocket::http::{ContentType, Header};
 use rocket::response::Redirect;
+use rocket::response::status::Custom;
 use rocket::serde::json::Json;
 use rocket::serde::msgpack::MsgPack;
 use rocket::{Build, Config, Response, Rocket, State};
@@ -19,43 +20,81 @@ use rocket_include_tera::{
 };
 use rust_embed::RustEmbed;
 use serde_derive::{Deserialize, Serialize};
-use sqlx::migrate::Migrator;
-use sqlx::postgres::PgPoolOptions;
-use std::{env, thread};
+use std::env;
 use std::ffi::OsStr;
 use std::hash::Hash;
-use std::path::PathBuf;
-use futures::StreamExt;
-use rocket::data::{ByteUnit, Limits, ToByteUnit};
+use std::path::{Path, PathBuf};
+use clap::{Parser, Subcommand, ValueEnum};
+use futures::{SinkExt, StreamExt};
+use rocket::form::{FromFormField, ValueField};
+use rocket::data::{ByteUnit, Data, FromData, Limits, ToByteUnit};
+use rocket::fairing::AdHoc;
+use rocket::http::Status;
+use rocket::outcome::Outcome;
+use rocket::Request;
 
 #[macro_use]
 extern crate rocket;
 use serde_json::json;
-use sqlx::{query, Connection, Pool, Postgres, QueryBuilder};
-use tracing::Level;
+use sqlx::query;
+use tracing::{event, Level};
 use tracing_subscriber::fmt::writer::MakeWriterExt;
-use sto::defs::{Executable, StackNode, StackNodeData, StoData};
+use sto::analysis::{
+    find_regressions, top_functions, Finding, GroupBy, ProfileCounts, RegressionConfig, TopFunctions,
+};
+use sto::export::{chrome_trace, folded, pprof_gz, speedscope, svg, ExportFormat, SvgOptions};
+use sto::dag::{
+    build_flamegraph, build_flamegraph_with, callgraph_nodes, diff_flamegraph, merge_nodes,
+    D3FlamegraphData, TreeOptions,
+};
+use sto::defs::{
+    parse_label, Executable, HashCollision, LabeledExecutable, Regression,
+    ServerInfo, StackNode, StackNodeData, StoData, StreamAck, MIN_PROTOCOL_VERSION,
+    PROTOCOL_VERSION, STREAM_ACK_EVERY,
+};
+use sto::import::{import, ImportFormat};
+use sto::retention::{self, RetentionConfig};
+use sto::store::{connect, ExecutableQuery, MemoryStore, RegressionQuery, Store};
+use sto::wire::{self, WireCompression, WireFormat};
 
 // #[derive(RustEmbed)]
 // #[folder = "d3-flame-graph/dist/"]
 // struct Dist;
 
-static DB_POOL: OnceCell<Pool<Postgres>> = OnceCell::new();
+// where everything is kept, picked by the DATABASE_URL scheme, or the file `server view` was
+// given. see sto::store.
+static STORE: OnceCell<Box<dyn Store>> = OnceCell::new();
 
-static MIGRATOR: Migrator = sqlx::migrate!();
+fn store() -> &'static dyn Store {
+    STORE.get().expect("err getting store").as_ref()
+}
 
-const BIND_LIMIT: usize = 65535;
+// STO_WEBHOOK_URLS, comma separated. regression findings for new builds get posted to each.
+static WEBHOOK_URLS: OnceCell<Vec<String>> = OnceCell::new();
+// STO_PUBLIC_URL, what links in webhook payloads start with.
+static PUBLIC_URL: OnceCell<String> = OnceCell::new();
+// STO_WEBHOOK_MIN_SAMPLES, how many samples a new build needs before it's compared, and
+// STO_WEBHOOK_DELAY_SECS, how often that's checked. see notify_webhooks.
+static WEBHOOK_MIN_SAMPLES: OnceCell<i64> = OnceCell::new();
+static WEBHOOK_DELAY: OnceCell<std::time::Duration> = OnceCell::new();
+// a build still short of the min samples after this many checks is given up on.
+const WEBHOOK_CHECKS: u32 = 60;
 
-#[derive(Deserialize, Serialize, Clone, Debug)]
-pub struct D3FlamegraphData {
-    pub name: String,
-    pub value: i64,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub filename: Option<String>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub line_number: Option<i32>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub children: Option<Vec<D3FlamegraphData>>,
+// rfc 3339 (2023-03-13T14:00:00Z) or unix seconds, for ?from=&to= style params.
+pub struct Timestamp(pub DateTime<Utc>);
+
+impl<'v> FromFormField<'v> for Timestamp {
+    fn from_value(field: ValueField<'v>) -> rocket::form::Result<'v, Self> {
+        if let Ok(x) = field.value.parse::<i64>() {
+            return match Utc.timestamp_opt(x, 0).single() {
+                Some(y) => Ok(Timestamp(y)),
+                None => Err(rocket::form::Error::validation("timestamp out of range").into()),
+            };
+        }
+        DateTime::parse_from_rfc3339(field.value)
+            .map(|x| Timestamp(x.with_timezone(&Utc)))
+            .map_err(|x| rocket::form::Error::validation(x.to_string()).into())
+    }
 }
 
 // #[get("/dist/<file..>")]
@@ -70,96 +109,433 @@ pub struct D3FlamegraphData {
 //     Some((content_type, asset.data))
 // }
 
-#[post("/data/samples", format = "json", data = "<data>")]
-async fn data_ingest(data: Json<StoData>) {
-    let mut deser_data = data.0;
-    let snd_vec = deser_data.stack_node_datas.into_iter();
-    let sn_vec = deser_data.stack_nodes.into_iter();
-    let pb_vec = deser_data.profiled_binaries.into_iter();
-    DB_POOL.get().expect("err getting db").acquire().await.expect("err getting db").transaction(
-        |mut conn|Box::pin(async move {
-            let mut qb_1: QueryBuilder<Postgres> = QueryBuilder::new(
-                "insert into stack_node_data(id, symbol, file, line_number) "
-            );
-            qb_1.push_values(snd_vec.take(BIND_LIMIT / 4), |mut b, snd| {
-                let id = snd.id as i64;
-                let line_no = match snd.line_number {
-                    Some(x) => {Some(x as i32)},
-                    None => {None}
-                };
-                b.push_bind(id)
-                    .push_bind(snd.symbol)
-                    .push_bind(snd.file)
-                    .push_bind(line_no);
-            });
-            qb_1.push(" ON CONFLICT DO NOTHING ");
-            let mut q1 = qb_1.build();
-            q1.execute(&mut *conn).await
-        })
-    ).await.expect("error in data insert");
+// processed size is what a batch took on the wire, split evenly over its executables.
+fn set_processed_size(data: &mut StoData, wire_size: usize) {
+    let n = data.profiled_binaries.len().max(1);
+    for (i, executable) in data.profiled_binaries.iter_mut().enumerate() {
+        let remainder = if i == 0 { wire_size % n } else { 0 };
+        executable.processed_data_size = (wire_size / n + remainder) as i64;
+    }
+}
 
-    DB_POOL.get().expect("err getting db").acquire().await.expect("err getting db").transaction(
-        |mut conn|Box::pin(async move {
-            let mut qb_3: QueryBuilder<Postgres> = QueryBuilder::new(
-                "insert into executable(id, event, build_id, basename, updated_at, sample_count, raw_data_size, processed_data_size) "
-            );
-            qb_3.push_values(pb_vec.take(BIND_LIMIT / 4), |mut b, pb| {
-                let id = pb.id as i64;
-                let updated_at = chrono::Utc::now();
-                let sample_count = pb.sample_count as i64;
-                let raw_data_size = pb.raw_data_size as i64;
-                let processed_data_size = pb.processed_data_size as i64;
-                b.push_bind(id)
-                    .push_bind(pb.event)
-                    .push_bind(pb.build_id)
-                    .push_bind(pb.basename)
-                    .push_bind(updated_at)
-                    .push_bind(sample_count)
-                    .push_bind(raw_data_size)
-                    .push_bind(processed_data_size);
-            });
-            qb_3.push(" ON CONFLICT (id) DO UPDATE SET sample_count = executable.sample_count + excluded.sample_count, updated_at = excluded.updated_at, raw_data_size = executable.raw_data_size + excluded.raw_data_size, processed_data_size = executable.processed_data_size + excluded.processed_data_size ");
-            let mut q3 = qb_3.build();
-            q3.execute(&mut *conn).await
-        })
-    ).await.expect("error in data insert");
+// negotiated from content-type (json or msgpack) and content-encoding (identity, gzip or zstd).
+pub struct WirePayload(pub StoData);
 
+#[rocket::async_trait]
+impl<'r> FromData<'r> for WirePayload {
+    type Error = Custom<String>;
 
-    DB_POOL.get().expect("err getting db").acquire().await.expect("err getting db").transaction(
-        |mut conn|Box::pin(async move {
-            let mut qb_2: QueryBuilder<Postgres> = QueryBuilder::new(
-                "insert into stack_node(id, parent_id, stack_node_data_id, executable_id, sample_count) "
-            );
-            qb_2.push_values(sn_vec.take(BIND_LIMIT / 4), |mut b, sn| {
-                let id = sn.id as i64;
-                let parent_id = match sn.parent_id {
-                    Some(x) => {Some(x as i64)},
-                    None => {None}
-                };
-                let snd_id = sn.stack_node_data_id as i64;
-                let pb_id = sn.executable_id as i64;
-                let sample_count = sn.sample_count as i64;
-                b.push_bind(id)
-                    .push_bind(parent_id)
-                    .push_bind(snd_id)
-                    .push_bind(pb_id)
-                    .push(sample_count);
-            });
-            qb_2.push(" ON CONFLICT (id) DO UPDATE SET sample_count = stack_node.sample_count + excluded.sample_count ");
-            let mut q2 = qb_2.build();
-            q2.execute(&mut *conn).await
+    async fn from_data(req: &'r Request<'_>, data: Data<'r>) -> rocket::data::Outcome<'r, Self> {
+        let format = match req.content_type() {
+            Some(ct) if ct.is_msgpack() => WireFormat::Msgpack,
+            Some(ct) if ct.is_json() => WireFormat::Json,
+            None => WireFormat::Json,
+            Some(ct) => {
+                return Outcome::Error((
+                    Status::UnsupportedMediaType,
+                    Custom(Status::UnsupportedMediaType, format!("unsupported content type {}", ct)),
+                ))
+            }
+        };
+        let encoding = req.headers().get_one("Content-Encoding");
+        let compression = match WireCompression::from_content_encoding(encoding) {
+            Some(x) => x,
+            None => {
+                return Outcome::Error((
+                    Status::UnsupportedMediaType,
+                    Custom(Status::UnsupportedMediaType, format!("unsupported content encoding {:?}", encoding)),
+                ))
+            }
+        };
+        let limit = req
+            .limits()
+            .get(format.to_string().to_lowercase())
+            .unwrap_or(Limits::JSON);
+        let bytes = match data.open(limit).into_bytes().await {
+            Ok(x) if x.is_complete() => x.into_inner(),
+            Ok(_) => {
+                return Outcome::Error((
+                    Status::PayloadTooLarge,
+                    Custom(Status::PayloadTooLarge, format!("payload exceeds limit of {}", limit)),
+                ))
+            }
+            Err(x) => {
+                return Outcome::Error((
+                    Status::BadRequest,
+                    Custom(Status::BadRequest, x.to_string()),
+                ))
+            }
+        };
+        let wire_size = bytes.len();
+        let decoded = tokio::task::spawn_blocking(move || {
+            wire::decode::<StoData>(&bytes, format, compression, limit.as_u64())
         })
-    ).await.expect("error in data insert");
+        .await;
+        match decoded {
+            Ok(Ok(mut x)) => {
+                event!(Level::DEBUG, "decoded {} bytes of {}/{}", wire_size, format, compression);
+                set_processed_size(&mut x, wire_size);
+                Outcome::Success(WirePayload(x))
+            }
+            // most likely a client speaking a protocol version we don't know, say so instead of a bare 422.
+            Ok(Err(x)) => Outcome::Error((
+                Status::UnprocessableEntity,
+                Custom(
+                    Status::UnprocessableEntity,
+                    format!(
+                        "unable to decode payload ({:#}), server speaks protocol versions {} through {}",
+                        x, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+                    ),
+                ),
+            )),
+            Err(x) => Outcome::Error((
+                Status::InternalServerError,
+                Custom(Status::InternalServerError, x.to_string()),
+            )),
+        }
+    }
+}
+
+#[get("/api/version")]
+async fn version() -> Json<ServerInfo> {
+    Json(ServerInfo::current())
+}
+
+#[get("/collisions")]
+async fn collisions() -> Result<Json<Vec<HashCollision>>, Custom<String>> {
+    Ok(Json(store().collisions().await.map_err(query_err)?))
 }
 
-#[get("/dag/<id>")]
-async fn data(id: i64) -> Json<D3FlamegraphData> {
+// stores a batch, then tells the webhooks about any new builds in it.
+async fn ingest(deser_data: StoData) -> Result<()> {
+    let new_builds = new_builds(&deser_data).await?;
+    store().ingest(deser_data).await?;
+    for (basename, build_id) in new_builds {
+        rocket::tokio::spawn(notify_webhooks(basename, build_id));
+    }
+    Ok(())
+}
+
+// (basename, build_id) of incoming executables that are a build we haven't seen before of a
+// basename we have. empty if there are no webhooks to tell.
+async fn new_builds(deser_data: &StoData) -> Result<Vec<(String, String)>> {
+    if WEBHOOK_URLS.get().map_or(true, |x| x.is_empty()) {
+        return Ok(Vec::new());
+    }
+    let basenames: Vec<String> = deser_data.profiled_binaries.iter().map(|x| x.basename.clone()).collect();
+    let known = store().builds(&basenames).await?;
+    let mut out: Vec<(String, String)> = Vec::new();
+    for pb in deser_data.profiled_binaries.iter() {
+        let build_id = match pb.build_id.as_ref() {
+            Some(x) => x,
+            None => continue,
+        };
+        let same_basename = known.iter().any(|(x, _)| *x == pb.basename);
+        let same_build = known.iter().any(|(x, y)| *x == pb.basename && y.as_ref() == Some(build_id));
+        let entry = (pb.basename.clone(), build_id.clone());
+        if same_basename && !same_build && !out.contains(&entry) {
+            out.push(entry);
+        }
+    }
+    Ok(out)
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct WebhookPayload {
+    pub basename: String,
+    pub base_build_id: Option<String>,
+    pub target_build_id: Option<String>,
+    pub findings: Vec<WebhookFinding>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct WebhookFinding {
+    pub symbol: String,
+    pub file: Option<String>,
+    pub line_number: Option<i32>,
+    pub pct_change: f64,
+    pub p_value: f64,
+    pub base_samples: i64,
+    pub target_samples: i64,
+    // flamegraphs of the newest base and target profiles, and the diff between them.
+    pub base_url: String,
+    pub target_url: String,
+    pub diff_url: String,
+}
+
+// compares a new build against the previous one and posts whatever regressed. the first upload
+// of a build kicks this off, but it waits until the build has STO_WEBHOOK_MIN_SAMPLES so a
+// stream's or agent's first batch isn't all that gets compared. whoever claims the build in the
+// store posts, so racing first batches (or several servers) only do it once. a claimed build is
+// never posted again, even if every webhook failed.
+async fn notify_webhooks(basename: String, build_id: String) {
+    let min_samples = WEBHOOK_MIN_SAMPLES.get().copied().unwrap_or_default();
+    let delay = WEBHOOK_DELAY.get().copied().unwrap_or_default();
+    let query = ExecutableQuery {
+        basename: Some(basename.clone()),
+        build_id: Some(build_id.clone()),
+        ..Default::default()
+    };
+    let mut checks = 0;
+    loop {
+        rocket::tokio::time::sleep(delay).await;
+        // executable sample_count counts frames, each sample has one root node though.
+        let ids: Vec<i64> = match store().executables(&query).await {
+            Ok(x) => x.iter().map(|y| y.executable.id).collect(),
+            Err(x) => {
+                event!(Level::WARN, "unable to count samples of {} {}: {}", basename, build_id, x);
+                return;
+            }
+        };
+        let samples: i64 = match store().nodes(&ids, None, None).await {
+            Ok(x) => x.iter().filter(|y| y.parent_id.is_none()).map(|y| y.sample_count).sum(),
+            Err(x) => {
+                event!(Level::WARN, "unable to count samples of {} {}: {}", basename, build_id, x);
+                return;
+            }
+        };
+        if samples >= min_samples {
+            break;
+        }
+        checks += 1;
+        if checks >= WEBHOOK_CHECKS {
+            event!(Level::INFO, "{} {} only got {} of {} samples, not comparing it", basename, build_id, samples, min_samples);
+            return;
+        }
+    }
+    match store().claim_notification(&basename, &build_id).await {
+        Ok(true) => {}
+        Ok(false) => {
+            event!(Level::DEBUG, "{} {} was already notified about", basename, build_id);
+            return;
+        }
+        Err(x) => {
+            event!(Level::WARN, "unable to claim {} {}: {}", basename, build_id, x);
+            return;
+        }
+    }
+
+    let filter = ExecutableFilter {
+        basename: Some(basename.clone()),
+        ..Default::default()
+    };
+    let report = match regression_report(&filter, None, Some(build_id.clone()), RegressionConfig::default()).await {
+        Ok(x) => x,
+        Err(x) => {
+            event!(Level::WARN, "no regression analysis for {} {}: {}", basename, build_id, x.1);
+            return;
+        }
+    };
+    if report.findings.is_empty() {
+        event!(Level::INFO, "no regressions in {} {}", basename, build_id);
+        return;
+    }
+    let public_url = PUBLIC_URL.get().map(|x| x.trim_end_matches('/')).unwrap_or_default();
+    // ids are newest first.
+    let (base_id, target_id) = (report.base_ids[0], report.target_ids[0]);
+    let payload = WebhookPayload {
+        basename: report.basename,
+        base_build_id: report.base_build_id,
+        target_build_id: report.target_build_id,
+        findings: report.findings.into_iter().map(|x| WebhookFinding {
+            symbol: x.symbol,
+            file: x.file,
+            line_number: x.line_number,
+            pct_change: x.pct_change,
+            p_value: x.p_value,
+            base_samples: x.base_samples,
+            target_samples: x.target_samples,
+            base_url: format!("{}/api/v1/dag/{}", public_url, base_id),
+            target_url: format!("{}/api/v1/dag/{}", public_url, target_id),
+            diff_url: format!("{}/api/v1/diff/{}/{}", public_url, base_id, target_id),
+        }).collect(),
+    };
+    post_webhooks(WEBHOOK_URLS.get().map(|x| x.as_slice()).unwrap_or_default(), &payload).await;
+}
+
+// a hung endpoint shouldn't keep a task (and its connection) around forever.
+async fn post_webhooks(urls: &[String], payload: &WebhookPayload) {
+    let client = match reqwest::Client::builder()
+        .connect_timeout(std::time::Duration::from_secs(5))
+        .timeout(std::time::Duration::from_secs(30))
+        .build()
+    {
+        Ok(x) => x,
+        Err(x) => {
+            event!(Level::WARN, "unable to build webhook client: {}", x);
+            return;
+        }
+    };
+    for url in urls {
+        match client.post(url).json(payload).send().await.and_then(|x| x.error_for_status()) {
+            Ok(_) => event!(Level::INFO, "posted {} regressions in {} {:?} to {}", payload.findings.len(), payload.basename, payload.target_build_id, url),
+            Err(x) => event!(Level::WARN, "webhook {} failed: {}", url, x),
+        }
+    }
+}
+
+#[post("/data/samples", data = "<data>")]
+async fn data_ingest(data: Result<WirePayload, Custom<String>>) -> Result<(), Custom<String>> {
+    let deser_data = data?.0;
+    deser_data
+        .check_version()
+        .map_err(|x| Custom(Status::BadRequest, x))?;
+    ingest(deser_data)
+        .await
+        .map_err(|x| Custom(Status::InternalServerError, x.to_string()))
+}
+
+// a profile from another tool in the body (perf-script, pprof or folded), converted the same way
+// the cli converts what it profiles, so old profiles can be compared w/ new ones. basename,
+// version and event default to what the profile itself says, see sto::import. label_key picks
+// which of a pprof's own labels to keep, none by default.
+#[post("/import/<format>?<basename>&<version>&<event>&<label>&<label_key>", data = "<data>")]
+async fn import_profile(
+    format: &str,
+    data: Data<'_>,
+    limits: &Limits,
+    basename: Option<String>,
+    version: Option<String>,
+    event: Option<String>,
+    label: Vec<String>,
+    label_key: Vec<String>,
+) -> Result<Json<Vec<Executable>>, Custom<String>> {
+    let format = ImportFormat::from_str(format, true).map_err(|x| Custom(Status::NotFound, x))?;
+    let labels = label
+        .iter()
+        .map(|x| parse_label(x).map_err(|y| Custom(Status::BadRequest, y)))
+        .collect::<Result<BTreeMap<String, String>, Custom<String>>>()?;
+    let limit = limits.get("import").unwrap_or(Limits::FILE);
+    let body = data
+        .open(limit)
+        .into_bytes()
+        .await
+        .map_err(|x| Custom(Status::BadRequest, x.to_string()))?;
+    if !body.is_complete() {
+        return Err(Custom(Status::PayloadTooLarge, "profile over the import limit".to_string()));
+    }
+    let input = body.into_inner();
+    let sto_data = rocket::tokio::task::spawn_blocking(move || {
+        let imported = import(format, &input, event.as_deref(), &label_key, limit.as_u64())?;
+        if imported.stacks.is_empty() {
+            return Err(anyhow!("nothing to import"));
+        }
+        imported.into_sto_data(basename, version, &labels, input.len() as i64)
+    })
+    .await
+    .expect("import task panicked")
+    .map_err(|x| Custom(Status::BadRequest, x.to_string()))?;
+    let executables = sto_data.profiled_binaries.clone();
+    ingest(sto_data)
+        .await
+        .map_err(|x| Custom(Status::InternalServerError, x.to_string()))?;
+    Ok(Json(executables))
+}
+
+// one long lived connection instead of a post per batch. every frame is a StoData encoded
+// per format/compression (text frames are always plain json) and is stored as it comes in, a
+// StreamAck goes back every ack_every frames so the client can bound how much it has in flight.
+// ack.frames only counts stored frames, so after an error the client knows which to resend.
+#[get("/data/stream?<format>&<compression>&<ack_every>")]
+fn data_stream(
+    ws: rocket_ws::WebSocket,
+    limits: &Limits,
+    format: Option<&str>,
+    compression: Option<&str>,
+    ack_every: Option<u64>,
+) -> Result<rocket_ws::Channel<'static>, Custom<String>> {
+    let format = match format {
+        Some(x) => WireFormat::from_str(x, true).map_err(|x| Custom(Status::BadRequest, x))?,
+        None => WireFormat::Msgpack,
+    };
+    let compression = match compression {
+        Some(x) => WireCompression::from_str(x, true).map_err(|x| Custom(Status::BadRequest, x))?,
+        None => WireCompression::Zstd,
+    };
+    let ack_every = ack_every.unwrap_or(STREAM_ACK_EVERY).max(1);
+    let limit = limits
+        .get(format.to_string().to_lowercase())
+        .unwrap_or(Limits::JSON);
+    let ws = ws.config(rocket_ws::Config {
+        max_message_size: Some(limit.as_u64() as usize),
+        max_frame_size: Some(limit.as_u64() as usize),
+        ..Default::default()
+    });
+
+    Ok(ws.channel(move |mut stream| Box::pin(async move {
+        let mut ack = StreamAck::default();
+        while let Some(message) = stream.next().await {
+            let (decoded, wire_size) = match message? {
+                rocket_ws::Message::Binary(x) => {
+                    (wire::decode::<StoData>(&x, format, compression, limit.as_u64()), x.len())
+                }
+                rocket_ws::Message::Text(x) => (
+                    wire::decode::<StoData>(
+                        x.as_bytes(),
+                        WireFormat::Json,
+                        WireCompression::None,
+                        limit.as_u64(),
+                    ),
+                    x.len(),
+                ),
+                rocket_ws::Message::Close(_) => break,
+                _ => continue,
+            };
+            let mut frame = match decoded
+                .map_err(|x| format!("unable to decode frame: {:#}", x))
+                .and_then(|x| x.check_version().map(|_| x))
+            {
+                Ok(x) => x,
+                Err(x) => {
+                    ack.error = Some(x);
+                    break;
+                }
+            };
+            set_processed_size(&mut frame, wire_size);
+            let samples = frame.samples();
+            if let Err(x) = ingest(frame).await {
+                ack.error = Some(x.to_string());
+                break;
+            }
+            ack.frames += 1;
+            ack.samples += samples;
+            if ack.frames % ack_every == 0 {
+                stream
+                    .send(rocket_ws::Message::Text(json!(ack).to_string().into()))
+                    .await?;
+            }
+        }
+        if let Some(x) = ack.error.as_ref() {
+            event!(Level::ERROR, "closing ingest stream after {} frames: {}", ack.frames, x);
+        }
+        // best effort, the client may well be gone by now.
+        let _ = stream
+            .send(rocket_ws::Message::Text(json!(ack).to_string().into()))
+            .await;
+        event!(Level::INFO, "ingest stream done, {} frames, {} samples", ack.frames, ack.samples);
+        Ok(())
+    })))
+}
+
+// min_pct, max_depth and root (a node_id) trim the tree down before it's sent, see TreeOptions.
+#[get("/dag/<id>?<from>&<to>&<min_pct>&<max_depth>&<root>")]
+async fn data(
+    id: i64,
+    from: Option<Timestamp>,
+    to: Option<Timestamp>,
+    min_pct: Option<f64>,
+    max_depth: Option<usize>,
+    root: Option<i64>,
+) -> Result<Json<D3FlamegraphData>, Custom<String>> {
     if id == 123 {
-        return Json(D3FlamegraphData {
+        return Ok(Json(D3FlamegraphData {
             name: "junk test data".to_string(),
             value: 12,
             filename: Some("/var/asdas/ffff.cpp".to_string()),
             line_number: Some(123),
+            delta: None,
+            frame_id: None,
+            node_id: None,
             children: Option::from(vec![
                 D3FlamegraphData {
                     name: "dqwd".to_string(),
@@ -167,6 +543,9 @@ async fn data(id: i64) -> Json<D3FlamegraphData> {
                     filename: None,
                     line_number: None,
                     children: None,
+                    delta: None,
+                    frame_id: None,
+                    node_id: None,
                 },
                 D3FlamegraphData {
                     name: "dsqwd".to_string(),
@@ -174,9 +553,12 @@ async fn data(id: i64) -> Json<D3FlamegraphData> {
                     filename: None,
                     line_number: None,
                     children: None,
+                    delta: None,
+                    frame_id: None,
+                    node_id: None,
                 },
             ]),
-        });
+        }));
     }
 
     // else {
@@ -193,96 +575,402 @@ async fn data(id: i64) -> Json<D3FlamegraphData> {
     // the dag should rly be made by some cool function in the db (or well i haven't tried that and want to see how it work).
     // for now this simpler.
 
-    let mut conn = DB_POOL.get().expect("err getting db").acquire().await.expect("err getting db");
-    let sn = sqlx::query_as!(StackNode, "select * from stack_node where executable_id=$1", id)
-        .fetch_all(&mut conn)
-        .await.expect("query err");
-
-    let snd = sqlx::query_as!(StackNodeData, "select d.id as id, d.symbol as symbol, d.file as file, d.line_number as line_number from stack_node_data d inner join stack_node n ON n.stack_node_data_id = d.id where n.executable_id = $1 ", id)
-        .fetch_all(&mut conn)
-        .await.expect("query err");
-    let pb = sqlx::query_as!(Executable, "select * from executable where id=$1", id)
-        .fetch_one(&mut conn)
-        .await.expect("query err");
-
-    let num: u64 = 100_000_000;
-
-    let data = thread::Builder::new().stack_size(num as usize * 0xFF).spawn(move || {
-        let sd_map: HashMap<i64, StackNodeData> = HashMap::from_iter(snd);
-        let sn_id_map: HashMap<i64, StackNode> = sn.iter().map(|x| (x.id.clone(), x.clone())).collect();
-        let mut sn_p_id_map: HashMap<i64, Vec<StackNode>> = HashMap::new();
-        // not great but can easily par map w/ rayon if need be.
-        let _exec: Vec<()> = sn_id_map.iter().filter(|(k,v)| v.parent_id.is_some()).map(|(k,v)| {
-            sn_p_id_map.entry(v.parent_id.unwrap()).and_modify(|e| (*e).push(v.clone())).or_insert(vec![v.clone()]);
-        }).collect();
-
-        // sorta a hack to make vis work w/o having to change.
-        fn build_dag(cur_id: i64, sn_id_map: &HashMap<i64, StackNode>, sd_map: &HashMap<i64, StackNodeData>, sn_p_id_map: &HashMap<i64, Vec<StackNode>>) -> D3FlamegraphData {
-            let cur_sn = sn_id_map.get(&cur_id).unwrap();
-            let cur_sd = sd_map.get(&(cur_sn.stack_node_data_id.clone())).unwrap();
-            let name = match cur_sd.clone().file{
-                Some(x) => {
-                    match x.contains("/"){
-                        true => {
-                            let (_, base_filename) = x.rsplit_once('/').unwrap();
-                            match cur_sd.line_number.clone() {
-                                Some(y) => {
-                                    format!("{}:{}:{}", cur_sd.symbol.clone(), base_filename, y)
-                                },
-                                None => {
-                                    format!("{}:{}", cur_sd.symbol.clone(), base_filename)
-                                }
-                            }
-                        },
-                        false => {
-                            match cur_sd.line_number.clone() {
-                                Some(y) => {
-                                    format!("{}:{}:{}", cur_sd.symbol.clone(), x, y)
-                                },
-                                None => {
-                                    format!("{}:{}", cur_sd.symbol.clone(), x)
-                                }
-                            }
-                        }
-                    }
-                },
-                None => {
-                    cur_sd.symbol.clone()
-                },
-            };
-            D3FlamegraphData {
-                name,
-                value: cur_sn.sample_count.clone(),
-                filename: cur_sd.file.clone(),
-                line_number: cur_sd.line_number.clone(),
-                children: match sn_p_id_map.get(&cur_id) {
-                    Some(id_list) => {
-                        Some(id_list.iter().map(|x| build_dag(x.id, sn_id_map, sd_map, sn_p_id_map)).collect())
-                    },
-                    None => { None }
-                },
-            }
-        };
-        // from all root nodes, recursively build out a dag.c
-        let children: Vec<D3FlamegraphData> = sn.iter().filter(|x| x.parent_id.is_none()).map(|x| build_dag(x.id, &sn_id_map, &sd_map, &sn_p_id_map) ).collect();
-        Json(D3FlamegraphData{
-            name: pb.basename,
-            value: children.iter().map(|x| x.value ).sum(),
-            filename: None,
-            line_number: None,
-            children: Some(children),
-        })
-    }).unwrap().join().unwrap();
-    data
+    if max_depth == Some(0) {
+        return Err(Custom(Status::BadRequest, "max_depth has to be at least 1".to_string()));
+    }
+    let pb = load_executable(id).await?.executable;
+    let filter = ExecutableFilter {
+        from,
+        to,
+        ..Default::default()
+    };
+    let sn = load_nodes(&[id], &filter).await?;
+    if let Some(x) = root {
+        if !sn.iter().any(|y| y.id == x) {
+            return Err(Custom(Status::NotFound, format!("no stack node {} in {}", x, id)));
+        }
+    }
+    let snd = load_node_datas(&[id]).await?;
+
+    let opts = TreeOptions {
+        min_pct,
+        max_depth,
+        root,
+    };
+    let data = rocket::tokio::task::spawn_blocking(move || {
+        Json(build_flamegraph_with(pb.basename, sn, snd, &opts))
+    }).await.expect("err building flamegraph");
+    Ok(data)
+}
+
+// target's flamegraph w/ per-node deltas against base, for the ui's differential mode.
+#[get("/diff/<base_id>/<target_id>")]
+async fn diff(base_id: i64, target_id: i64) -> Result<Json<D3FlamegraphData>, Custom<String>> {
+    let (base, target) = (load_executable(base_id).await?.executable, load_executable(target_id).await?.executable);
+    let base_sn = load_nodes(&[base_id], &ExecutableFilter::default()).await?;
+    let target_sn = load_nodes(&[target_id], &ExecutableFilter::default()).await?;
+    // both, paths only in base are drawn too.
+    let snd = load_node_datas(&[base_id, target_id]).await?;
+
+    let name = format!("{} {} vs {} {}", target.basename, target.build_id.unwrap_or_default(), base.basename, base.build_id.unwrap_or_default());
+    let data = rocket::tokio::task::spawn_blocking(move || {
+        Json(diff_flamegraph(name, base_sn, target_sn, snd))
+    }).await.expect("err building flamegraph");
+    Ok(data)
+}
+
+// a profile in some other tool's format, picked by extension: /export/12.pb.gz. see sto::export.
+// lines puts file:line in frame names for formats that only have names (folded).
+#[get("/export/<file>?<lines>")]
+async fn export(file: &str, lines: Option<bool>) -> Result<(ContentType, Vec<u8>), Custom<String>> {
+    let (id, format) = match file.split_once('.') {
+        Some((x, y)) => (x.parse::<i64>().ok(), ExportFormat::from_extension(y)),
+        None => (None, None),
+    };
+    let (id, format) = match (id, format) {
+        (Some(x), Some(y)) => (x, y),
+        _ => return Err(Custom(Status::NotFound, format!("unknown export {}", file))),
+    };
+    let LabeledExecutable { executable: pb, labels } = load_executable(id).await?;
+    let sn = load_nodes(&[id], &ExecutableFilter::default()).await?;
+    let snd = load_node_datas(&[id]).await?;
+    let body = rocket::tokio::task::spawn_blocking(move || match format {
+        ExportFormat::Pprof => pprof_gz(&pb, &labels, &sn, &snd),
+        ExportFormat::Folded => Ok(folded(&sn, &snd, lines.unwrap_or(false)).into_bytes()),
+        ExportFormat::Speedscope => Ok(speedscope(&pb.basename, &sn, &snd).into_bytes()),
+        ExportFormat::ChromeTrace => Ok(chrome_trace(&pb.basename, &sn, &snd).into_bytes()),
+    }).await.expect("err exporting")
+        .map_err(|x| Custom(Status::InternalServerError, x.to_string()))?;
+    let content_type = ContentType::parse_flexible(format.content_type()).unwrap_or(ContentType::Binary);
+    Ok((content_type, body))
+}
+
+// standalone svg flamegraph, for ci reports, tickets and such. title defaults to the binary and
+// version, see SvgOptions for the rest.
+#[get("/svg/<id>?<title>&<width>&<min_width>&<search>&<lines>")]
+async fn svg_flamegraph(
+    id: i64,
+    title: Option<String>,
+    width: Option<usize>,
+    min_width: Option<f64>,
+    search: Option<String>,
+    lines: Option<bool>,
+) -> Result<(ContentType, Vec<u8>), Custom<String>> {
+    let pb = load_executable(id).await?.executable;
+    let sn = load_nodes(&[id], &ExecutableFilter::default()).await?;
+    let snd = load_node_datas(&[id]).await?;
+    let opts = SvgOptions {
+        title: Some(title.unwrap_or_else(|| match pb.build_id.as_ref() {
+            Some(x) => format!("{} {}", pb.basename, x),
+            None => pb.basename.clone(),
+        })),
+        width,
+        min_width,
+        search,
+        lines: lines.unwrap_or(false),
+    };
+    let body = rocket::tokio::task::spawn_blocking(move || svg(&sn, &snd, &opts))
+        .await.expect("err rendering svg")
+        .map_err(|x| Custom(Status::InternalServerError, x.to_string()))?;
+    Ok((ContentType::SVG, body))
 }
 
 #[get("/data/<id>")]
-async fn metadata(id: i64) -> Json<Executable> {
-    let mut conn = DB_POOL.get().expect("err getting db").acquire().await.expect("err getting db");
-    let pb = sqlx::query_as!(Executable, "select * from executable where id=$1", id)
-        .fetch_one(&mut conn)
-        .await.expect("query err");
-    Json(pb)
+async fn metadata(id: i64) -> Result<Json<Executable>, Custom<String>> {
+    Ok(Json(load_executable(id).await?.executable))
+}
+
+// narrows down which executables a query looks at. every label (key=value) has to match, host
+// is shorthand for label=hostname=..., and from/to only keep executables w/ samples in the window.
+#[derive(FromForm, Default)]
+pub struct ExecutableFilter {
+    pub basename: Option<String>,
+    pub build_id: Option<String>,
+    pub host: Option<String>,
+    pub label: Vec<String>,
+    pub from: Option<Timestamp>,
+    pub to: Option<Timestamp>,
+}
+
+impl ExecutableFilter {
+    pub fn labels(&self) -> Result<Vec<(String, String)>, Custom<String>> {
+        let mut labels: Vec<(String, String)> = self.label
+            .iter()
+            .filter(|x| !x.trim().is_empty())
+            .map(|x| parse_label(x).map_err(|y| Custom(Status::BadRequest, y)))
+            .collect::<Result<_, _>>()?;
+        if let Some(x) = self.host.as_ref() {
+            labels.push(("hostname".to_string(), x.clone()));
+        }
+        Ok(labels)
+    }
+
+    pub fn has_range(&self) -> bool {
+        self.from.is_some() || self.to.is_some()
+    }
+
+    pub fn query(&self) -> Result<ExecutableQuery, Custom<String>> {
+        Ok(ExecutableQuery {
+            basename: self.basename.clone(),
+            build_id: self.build_id.clone(),
+            labels: self.labels()?,
+            from: self.from.as_ref().map(|x| x.0),
+            to: self.to.as_ref().map(|x| x.0),
+        })
+    }
+}
+
+// a store that fails is a 500, the handler doesn't go down w/ it.
+fn query_err(x: anyhow::Error) -> Custom<String> {
+    event!(Level::ERROR, "query err: {:#}", x);
+    Custom(Status::InternalServerError, format!("query err: {:#}", x))
+}
+
+async fn filtered_executables(filter: &ExecutableFilter) -> Result<Vec<LabeledExecutable>, Custom<String>> {
+    store().executables(&filter.query()?).await.map_err(query_err)
+}
+
+#[get("/executables?<filter..>")]
+async fn executables(filter: ExecutableFilter) -> Result<Json<Vec<LabeledExecutable>>, Custom<String>> {
+    Ok(Json(filtered_executables(&filter).await?))
+}
+
+// stack nodes of all the given executables. counts come from the buckets if there's a time range.
+async fn load_nodes(ids: &[i64], filter: &ExecutableFilter) -> Result<Vec<StackNode>, Custom<String>> {
+    store()
+        .nodes(ids, filter.from.as_ref().map(|x| x.0), filter.to.as_ref().map(|x| x.0))
+        .await
+        .map_err(query_err)
+}
+
+// one executable w/ its labels, a 404 if there's no such thing.
+async fn load_executable(id: i64) -> Result<LabeledExecutable, Custom<String>> {
+    match store().executable(id).await.map_err(query_err)? {
+        Some(x) => Ok(x),
+        None => Err(Custom(Status::NotFound, "no such executable".to_string())),
+    }
+}
+
+async fn load_node_datas(ids: &[i64]) -> Result<Vec<StackNodeData>, Custom<String>> {
+    store().node_datas(ids).await.map_err(query_err)
+}
+
+// one flamegraph out of every executable the filter matches (all hosts in a region for a build,
+// say), w/ identical call paths merged. counts come from the buckets if there's a time range.
+#[get("/aggregate?<filter..>")]
+async fn aggregate(filter: ExecutableFilter) -> Result<Json<D3FlamegraphData>, Custom<String>> {
+    let pb = filtered_executables(&filter).await?;
+    let ids: Vec<i64> = pb.iter().map(|x| x.executable.id).collect();
+    let sn = load_nodes(&ids, &filter).await?;
+    let snd = load_node_datas(&ids).await?;
+
+    let mut basenames: Vec<&str> = pb.iter().map(|x| x.executable.basename.as_str()).collect();
+    basenames.sort();
+    basenames.dedup();
+    let name = format!("{} ({} profiles)", basenames.join(", "), pb.len());
+
+    let data = rocket::tokio::task::spawn_blocking(move || {
+        Json(build_flamegraph(name, merge_nodes(sn), snd))
+    }).await.expect("err building flamegraph");
+    Ok(data)
+}
+
+// findRegressions w/ the filters pushed down. from defaults to the start of yesterday, like the
+// db function.
+#[get("/regressions?<from>&<to>&<basename>&<min_samples>&<min_pct_change>&<limit>")]
+async fn regressions(
+    from: Option<Timestamp>,
+    to: Option<Timestamp>,
+    basename: Option<String>,
+    min_samples: Option<i64>,
+    min_pct_change: Option<f64>,
+    limit: Option<i64>,
+) -> Result<Json<Vec<Regression>>, Custom<String>> {
+    let yesterday = (Utc::now() - Duration::days(1))
+        .duration_trunc(Duration::days(1))
+        .expect("date out of range");
+    let query = RegressionQuery {
+        from: from.map_or(yesterday, |x| x.0),
+        to: to.map(|x| x.0),
+        basename,
+        min_samples: min_samples.unwrap_or(0),
+        min_pct_change: min_pct_change.unwrap_or(0.0),
+        limit: limit.unwrap_or(100),
+    };
+    Ok(Json(store().regressions(&query).await.map_err(query_err)?))
+}
+
+// the n (default 20) hottest functions of a profile, by self and by total samples.
+#[get("/top/<id>?<n>&<by>&<from>&<to>")]
+async fn top(id: i64, n: Option<usize>, by: Option<String>, from: Option<Timestamp>, to: Option<Timestamp>) -> Result<Json<TopFunctions>, Custom<String>> {
+    let group_by = match by {
+        Some(x) => x.parse().map_err(|y| Custom(Status::BadRequest, y))?,
+        None => GroupBy::Symbol,
+    };
+    // a typo'd id is a 404, not an empty (and passing) list.
+    load_executable(id).await?;
+    let filter = ExecutableFilter {
+        from,
+        to,
+        ..Default::default()
+    };
+    let sn = load_nodes(&[id], &filter).await?;
+    let snd: HashMap<i64, StackNodeData> = HashMap::from_iter(load_node_datas(&[id]).await?);
+    Ok(Json(top_functions(&sn, &snd, group_by, n.unwrap_or(20))))
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct CallGraph {
+    pub symbol: String,
+    pub samples: i64,
+    // inverted, children are callers.
+    pub callers: D3FlamegraphData,
+    pub callees: D3FlamegraphData,
+}
+
+// who calls a symbol and what it calls, over every place it shows up in the profile. frames are
+// picked by symbol (optionally narrowed by file and line), or directly by stack_node_data id.
+#[get("/callgraph/<id>?<symbol>&<file>&<line>&<frame>")]
+async fn callgraph(
+    id: i64,
+    symbol: Option<String>,
+    file: Option<String>,
+    line: Option<i32>,
+    frame: Option<i64>,
+) -> Result<Json<CallGraph>, Custom<String>> {
+    let snd = load_node_datas(&[id]).await?;
+    let frames: HashSet<i64> = snd
+        .iter()
+        .filter(|x| match (frame, symbol.as_ref()) {
+            (Some(y), _) => x.id == y,
+            (None, Some(y)) => x.symbol == *y
+                && file.as_ref().map_or(true, |z| x.file.as_ref() == Some(z))
+                && line.map_or(true, |z| x.line_number == Some(z)),
+            (None, None) => false,
+        })
+        .map(|x| x.id)
+        .collect();
+    let name = match snd.iter().find(|x| frames.contains(&x.id)) {
+        Some(x) => x.symbol.clone(),
+        None => return Err(Custom(Status::NotFound, "no matching frames, pass symbol= or frame=".to_string())),
+    };
+    let sn = load_nodes(&[id], &ExecutableFilter::default()).await?;
+
+    let data = rocket::tokio::task::spawn_blocking(move || {
+        let (callers, callees) = callgraph_nodes(&sn, &frames);
+        let callees = build_flamegraph(name.clone(), callees, snd.clone());
+        CallGraph {
+            symbol: name.clone(),
+            samples: callees.value,
+            callers: build_flamegraph(name, callers, snd),
+            callees,
+        }
+    }).await.expect("err building callgraph");
+    Ok(Json(data))
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct RegressionReport {
+    pub basename: String,
+    pub base_build_id: Option<String>,
+    pub target_build_id: Option<String>,
+    pub base_ids: Vec<i64>,
+    pub target_ids: Vec<i64>,
+    pub config: RegressionConfig,
+    pub findings: Vec<Finding>,
+}
+
+// compares every profile of the base build against every profile of the target build (both
+// within whatever else the filter narrows down to, e.g. a host or time range). w/o base/target
+// it's the newest build against the one before it.
+// whichever of base and target isn't given. target is the newest build, base the newest one
+// whose executables all came before target's, so it's what target replaced. builds w/o an id
+// can't be asked for, so they're never picked. pb is newest first.
+fn pick_builds(pb: &[LabeledExecutable], base: Option<String>, target: Option<String>) -> (Option<String>, Option<String>) {
+    let target = target.or_else(|| pb.iter().find_map(|x| x.executable.build_id.clone()));
+    let target_created = pb
+        .iter()
+        .filter(|x| x.executable.build_id == target)
+        .filter_map(|x| x.executable.created_at)
+        .min();
+    let base = base.or_else(|| {
+        pb.iter()
+            .filter(|x| x.executable.build_id.is_some() && x.executable.build_id != target)
+            .find(|x| matches!((x.executable.created_at, target_created), (Some(a), Some(b)) if a < b))
+            .and_then(|x| x.executable.build_id.clone())
+    });
+    (base, target)
+}
+
+async fn regression_report(
+    filter: &ExecutableFilter,
+    base: Option<String>,
+    target: Option<String>,
+    config: RegressionConfig,
+) -> Result<RegressionReport, Custom<String>> {
+    let basename = match filter.basename.as_ref() {
+        Some(x) => x.clone(),
+        None => return Err(Custom(Status::BadRequest, "basename is required".to_string())),
+    };
+    let pb = filtered_executables(filter).await?;
+    let (base, target) = pick_builds(&pb, base, target);
+    if base.is_none() || target.is_none() || base == target {
+        return Err(Custom(Status::BadRequest, format!("need two builds of {} to compare", basename)));
+    }
+    let ids_of = |build: &Option<String>| -> Vec<i64> {
+        pb.iter().filter(|x| x.executable.build_id == *build).map(|x| x.executable.id).collect()
+    };
+    let (base_ids, target_ids) = (ids_of(&base), ids_of(&target));
+    let all_ids: Vec<i64> = base_ids.iter().chain(target_ids.iter()).copied().collect();
+    let mut sn_by_exe: HashMap<i64, Vec<StackNode>> = HashMap::new();
+    for x in load_nodes(&all_ids, filter).await? {
+        sn_by_exe.entry(x.executable_id).or_default().push(x);
+    }
+    let counts = |ids: &[i64]| -> Vec<ProfileCounts> {
+        ids.iter()
+            .map(|x| ProfileCounts::from_nodes(sn_by_exe.get(x).map(|y| y.as_slice()).unwrap_or_default()))
+            .collect()
+    };
+    let snd: HashMap<i64, StackNodeData> = HashMap::from_iter(load_node_datas(&all_ids).await?);
+    let findings = find_regressions(&counts(&base_ids), &counts(&target_ids), &snd, &config);
+    Ok(RegressionReport {
+        basename,
+        base_build_id: base,
+        target_build_id: target,
+        base_ids,
+        target_ids,
+        config,
+        findings,
+    })
+}
+
+// significance tested regressions between two builds, see sto::analysis.
+#[get("/analysis/regressions?<base>&<target>&<metric>&<min_samples>&<min_pct_change>&<max_p_value>&<confidence>&<filter..>")]
+async fn analysis_regressions(
+    base: Option<String>,
+    target: Option<String>,
+    metric: Option<String>,
+    min_samples: Option<i64>,
+    min_pct_change: Option<f64>,
+    max_p_value: Option<f64>,
+    confidence: Option<f64>,
+    filter: ExecutableFilter,
+) -> Result<Json<RegressionReport>, Custom<String>> {
+    let defaults = RegressionConfig::default();
+    let config = RegressionConfig {
+        metric: match metric {
+            Some(x) => x.parse().map_err(|y| Custom(Status::BadRequest, y))?,
+            None => defaults.metric,
+        },
+        min_samples: min_samples.unwrap_or(defaults.min_samples),
+        min_pct_change: min_pct_change.unwrap_or(defaults.min_pct_change),
+        max_p_value: max_p_value.unwrap_or(defaults.max_p_value),
+        confidence: confidence.unwrap_or(defaults.confidence),
+    };
+    if !(0.0..1.0).contains(&config.confidence) {
+        return Err(Custom(Status::BadRequest, "confidence must be in [0, 1)".to_string()));
+    }
+    Ok(Json(regression_report(&filter, base, target, config).await?))
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -295,24 +983,31 @@ pub struct TemplateListing{
     pub name: String,
     pub id: i64,
     pub date: String,
+    pub labels: String,
 }
 
 
-#[get("/")]
+#[get("/?<filter..>")]
 async fn index(
     cm: &State<TeraContextManager>,
     etag_if_none_match: EtagIfNoneMatch<'_>,
+    filter: ExecutableFilter,
 ) -> TeraResponse {
     println!("Generate index-2 and cache it...");
     let dummy_listing = TemplateListing{
         name: "somename".to_string(),
         id: 123,
-        date: Utc::now().format("%Y-%m-%d %H:%M:%S").to_string()
+        date: Utc::now().format("%Y-%m-%d %H:%M:%S").to_string(),
+        labels: "".to_string(),
     };
-    let mut conn = DB_POOL.get().expect("err getting db").acquire().await.expect("err getting db");
-    let pb: Vec<Executable> = sqlx::query_as!(Executable, "select * from executable")
-    .fetch_all(&mut conn).await.expect("query err");
-    let mut template_listing: Vec<TemplateListing> = pb.iter().map(|x| TemplateListing{ name: x.basename.clone(), id: x.id, date: x.created_at.unwrap().format("%Y-%m-%d %H:%M:%S").to_string() } ).collect();
+    // a bad label filter just lists nothing.
+    let pb = filtered_executables(&filter).await.unwrap_or_default();
+    let mut template_listing: Vec<TemplateListing> = pb.iter().map(|x| TemplateListing{
+        name: x.executable.basename.clone(),
+        id: x.executable.id,
+        date: x.executable.created_at.unwrap().format("%Y-%m-%d %H:%M:%S").to_string(),
+        labels: x.labels.iter().map(|(k, v)| format!("{}={}", k, v)).collect::<Vec<String>>().join(" "),
+    } ).collect();
     template_listing.push(dummy_listing);
     tera_response!(
         cm,
@@ -322,6 +1017,33 @@ async fn index(
     )
 }
 
+// STO_RETENTION, see sto::retention. w/o it nothing is ever deleted.
+async fn enforce_retention(config: RetentionConfig) {
+    let mut interval = rocket::tokio::time::interval(std::time::Duration::from_secs(config.interval_secs));
+    loop {
+        interval.tick().await;
+        match retention::enforce(store(), &config, Utc::now()).await {
+            Ok(x) => event!(Level::INFO, "retention: downsampled {} buckets, deleted {} executables, collected {} frames", x.downsampled, x.deleted, x.collected),
+            Err(x) => event!(Level::WARN, "retention failed: {}", x),
+        }
+    }
+}
+
+// everything else is configured w/ DATABASE_URL and the STO_* envvars.
+#[derive(Parser, Debug)]
+#[command(author, version, about = "serves profiles uploaded by the cli, and the ui to look at them.")]
+struct ServerArgs {
+    #[command(subcommand)]
+    command: Option<ServerCommand>,
+}
+
+#[derive(Subcommand, Debug)]
+enum ServerCommand {
+    // what `cli view` runs.
+    #[command(about = "serve a profile saved w/ the cli's --output-file on localhost, straight from memory, no database needed.")]
+    View { file: PathBuf },
+}
+
 #[rocket::main]
 async fn main() -> Result<(), anyhow::Error> {
     dotenvy::dotenv()?;
@@ -337,33 +1059,88 @@ async fn main() -> Result<(), anyhow::Error> {
           .with_filter(tracing_subscriber::filter::LevelFilter::from_level(Level::DEBUG)))
       .init();
 
-    let db = env::var("DATABASE_URL").expect("error, DATABASE_URL envvar must be set.");
+    let view = ServerArgs::parse().command.map(|ServerCommand::View { file }| file);
 
-    DB_POOL.set(
-        PgPoolOptions::new()
-            .max_connections(100)
-            .connect(db.as_str())
-            .await?,
-    );
+    match view.as_ref() {
+        Some(file) => {
+            let mut data: StoData = wire::decode_file(&std::fs::read(file)?, u64::MAX)?;
+            data.check_version().map_err(|x| anyhow!(x))?;
+            event!(Level::INFO, "loaded {} stack nodes from {}", data.stack_nodes.len(), file.display());
+            if STORE.set(Box::new(MemoryStore::new(data))).is_err() {
+                return Err(anyhow!("store already set"));
+            }
+        }
+        None => {
+            let db = env::var("DATABASE_URL").expect("error, DATABASE_URL envvar must be set.");
+            if STORE.set(connect(db.as_str()).await?).is_err() {
+                return Err(anyhow!("store already set"));
+            }
+            if let Ok(x) = env::var("STO_RETENTION") {
+                rocket::tokio::spawn(enforce_retention(RetentionConfig::load(Path::new(&x))?));
+            }
+        }
+    }
 
-    MIGRATOR
-        .run(DB_POOL.get().expect("err getting db pool"))
-        .await?;
+    WEBHOOK_URLS.set(
+        env::var("STO_WEBHOOK_URLS")
+            .unwrap_or_default()
+            .split(',')
+            .map(|x| x.trim().to_string())
+            .filter(|x| !x.is_empty())
+            .collect(),
+    ).expect("webhook urls already set");
+    PUBLIC_URL.set(env::var("STO_PUBLIC_URL").unwrap_or_else(|_| "http://localhost:8000".to_string()))
+        .expect("public url already set");
+    let env_num = |name: &str, default: u64| -> Result<u64> {
+        match env::var(name) {
+            Ok(x) => x.parse().map_err(|y| anyhow!("bad {} {}: {}", name, x, y)),
+            Err(_) => Ok(default),
+        }
+    };
+    WEBHOOK_MIN_SAMPLES.set(env_num("STO_WEBHOOK_MIN_SAMPLES", 1000)? as i64)
+        .expect("webhook min samples already set");
+    WEBHOOK_DELAY.set(std::time::Duration::from_secs(env_num("STO_WEBHOOK_DELAY_SECS", 60)?))
+        .expect("webhook delay already set");
 
     let figment = rocket::Config::figment()
         .merge(("port", 8000))
-        .merge(("address", "0.0.0.0"))
-        .merge(("limits", Limits::new().limit("json", 1000.mebibytes())));
+        .merge(("address", if view.is_some() { "127.0.0.1" } else { "0.0.0.0" }))
+        .merge((
+            "limits",
+            Limits::new()
+                .limit("json", 1000.mebibytes())
+                .limit("msgpack", 1000.mebibytes())
+                // bodies of /import, and what a gzipped pprof in one can unpack to.
+                .limit("import", 1000.mebibytes()),
+        ));
 
 
-    let _rocket = rocket::custom(figment)
+    let rocket = rocket::custom(figment)
         .attach(TeraResponse::fairing(|tera| {
             tera_resources_initialize!(
                 tera,
                 "index" => "src/templates/index.tera",
             );
-        }))
-        .mount("/", routes![index, data, data_ingest, metadata])
+        }));
+    let rocket = match view {
+        // read only, nothing gets written back to the file.
+        Some(_) => rocket
+            .mount("/", routes![index, data, metadata, version])
+            .mount("/api/v1", routes![data, metadata, executables, aggregate, diff, top, callgraph, export, svg_flamegraph])
+            .attach(AdHoc::on_liftoff("open browser", |rocket| Box::pin(async move {
+                let url = format!("http://127.0.0.1:{}/", rocket.config().port);
+                let opener = if cfg!(target_os = "macos") { "open" } else { "xdg-open" };
+                if let Err(x) = std::process::Command::new(opener).arg(&url).spawn() {
+                    event!(Level::WARN, "couldn't open a browser: {}", x);
+                }
+                event!(Level::INFO, "viewing at {}", url);
+            }))),
+        // unprefixed routes are kept around for clients that predate /api/v1.
+        None => rocket
+            .mount("/", routes![index, data, data_ingest, metadata, version])
+            .mount("/api/v1", routes![data, data_ingest, data_stream, metadata, collisions, executables, aggregate, diff, regressions, analysis_regressions, top, callgraph, export, svg_flamegraph, import_profile]),
+    };
+    let _rocket = rocket
         .ignite()
         .await?
         .launch()
@@ -371,3 +1148,120 @@ async fn main() -> Result<(), anyhow::Error> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rocket::tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use rocket::tokio::net::TcpListener;
+
+    // answers one request w/ a 200 and hands back its body.
+    async fn listen_once() -> (String, rocket::tokio::task::JoinHandle<Vec<u8>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let handle = rocket::tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request: Vec<u8> = Vec::new();
+            let mut buf = [0u8; 4096];
+            loop {
+                let n = socket.read(&mut buf).await.unwrap();
+                assert!(n > 0, "connection closed before the whole request came in");
+                request.extend_from_slice(&buf[..n]);
+                let text = String::from_utf8_lossy(&request).to_string();
+                let Some(end) = text.find("\r\n\r\n") else { continue };
+                let length: usize = text[..end]
+                    .lines()
+                    .find_map(|x| x.to_lowercase().strip_prefix("content-length:").map(|y| y.trim().parse().unwrap()))
+                    .unwrap_or(0);
+                if request.len() >= end + 4 + length {
+                    socket.write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n").await.unwrap();
+                    return request[end + 4..end + 4 + length].to_vec();
+                }
+            }
+        });
+        (url, handle)
+    }
+
+    #[rocket::async_test]
+    async fn post_webhooks_sends_the_payload() {
+        let (url, body) = listen_once().await;
+        let payload = WebhookPayload {
+            basename: "myapp".to_string(),
+            base_build_id: Some("1.0".to_string()),
+            target_build_id: Some("1.1".to_string()),
+            findings: vec![WebhookFinding {
+                symbol: "slow_path".to_string(),
+                file: Some("src/lib.rs".to_string()),
+                line_number: Some(42),
+                pct_change: 250.0,
+                p_value: 0.001,
+                base_samples: 10,
+                target_samples: 35,
+                base_url: "http://sto/api/v1/dag/1".to_string(),
+                target_url: "http://sto/api/v1/dag/2".to_string(),
+                diff_url: "http://sto/api/v1/diff/1/2".to_string(),
+            }],
+        };
+        post_webhooks(&[url], &payload).await;
+        let posted: serde_json::Value = serde_json::from_slice(&body.await.unwrap()).unwrap();
+        assert_eq!(
+            posted,
+            json!({
+                "basename": "myapp",
+                "base_build_id": "1.0",
+                "target_build_id": "1.1",
+                "findings": [{
+                    "symbol": "slow_path",
+                    "file": "src/lib.rs",
+                    "line_number": 42,
+                    "pct_change": 250.0,
+                    "p_value": 0.001,
+                    "base_samples": 10,
+                    "target_samples": 35,
+                    "base_url": "http://sto/api/v1/dag/1",
+                    "target_url": "http://sto/api/v1/dag/2",
+                    "diff_url": "http://sto/api/v1/diff/1/2",
+                }],
+            })
+        );
+    }
+
+    #[rocket::async_test]
+    async fn only_one_claim_per_build() {
+        for url in ["memory:", "sqlite::memory:"] {
+            let store = connect(url).await.unwrap();
+            assert!(store.claim_notification("myapp", "1.1").await.unwrap(), "{}", url);
+            assert!(!store.claim_notification("myapp", "1.1").await.unwrap(), "{}", url);
+            assert!(store.claim_notification("myapp", "1.2").await.unwrap(), "{}", url);
+        }
+    }
+
+    #[test]
+    fn older_builds_are_the_base() {
+        let start = Utc::now();
+        // newest first: 3, then one w/o a build id, 2, 1.
+        let pb: Vec<LabeledExecutable> = [Some("3"), None, Some("2"), Some("1")]
+            .iter()
+            .enumerate()
+            .map(|(i, build)| LabeledExecutable {
+                executable: Executable {
+                    id: i as i64,
+                    event: "cycles".to_string(),
+                    build_id: build.map(|x| x.to_string()),
+                    basename: "myapp".to_string(),
+                    updated_at: None,
+                    created_at: Some(start - Duration::hours(i as i64)),
+                    sample_count: 1,
+                    raw_data_size: 0,
+                    processed_data_size: 0,
+                },
+                labels: BTreeMap::new(),
+            })
+            .collect();
+        let build = |x: &str| Some(x.to_string());
+        assert_eq!(pick_builds(&pb, None, None), (build("2"), build("3")));
+        assert_eq!(pick_builds(&pb, None, build("2")), (build("1"), build("2")));
+        assert_eq!(pick_builds(&pb, None, build("1")), (None, build("1")));
+        assert_eq!(pick_builds(&pb, build("1"), None), (build("1"), build("3")));
+    }
+}
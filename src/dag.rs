@@ -0,0 +1,333 @@
+use serde_derive::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+
+use crate::defs::{StackNode, StackNodeData};
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct D3FlamegraphData {
+    pub name: String,
+    pub value: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub filename: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub line_number: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub children: Option<Vec<D3FlamegraphData>>,
+    // only set on diffs, target minus (normalized) base samples. what d3-flame-graph's
+    // differential color mapper reads.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub delta: Option<i64>,
+    // stack_node_data id, for asking about this frame (e.g. /callgraph) from the ui.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub frame_id: Option<i64>,
+    // stack node id, for rooting /dag at this node. trees of merged nodes (aggregate, callgraph)
+    // have ids that only mean something within that response, diffs don't have any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub node_id: Option<i64>,
+}
+
+// symbol:basename(file):line, w/ whatever parts are known.
+pub fn frame_name(sd: &StackNodeData) -> String {
+    match sd.file.as_ref() {
+        Some(x) => {
+            let base_filename = match x.rsplit_once('/') {
+                Some((_, y)) => y,
+                None => x.as_str(),
+            };
+            match sd.line_number {
+                Some(y) => format!("{}:{}:{}", sd.symbol, base_filename, y),
+                None => format!("{}:{}", sd.symbol, base_filename),
+            }
+        }
+        None => sd.symbol.clone(),
+    }
+}
+
+// what to leave out of a flamegraph, so big profiles don't ship the whole tree to the browser.
+#[derive(Debug, Clone, Default)]
+pub struct TreeOptions {
+    // nodes w/ less than this percent of the (root's) samples are dropped, children and all.
+    pub min_pct: Option<f64>,
+    // levels of frames to keep below the root, 1 is just the outermost frames.
+    pub max_depth: Option<usize>,
+    // stack node id to root the tree at, instead of the whole profile.
+    pub root: Option<i64>,
+}
+
+pub fn build_flamegraph(name: String, sn: Vec<StackNode>, snd: Vec<StackNodeData>) -> D3FlamegraphData {
+    build_tree(name, sn, snd, None, &TreeOptions::default())
+}
+
+pub fn build_flamegraph_with(name: String, sn: Vec<StackNode>, snd: Vec<StackNodeData>, opts: &TreeOptions) -> D3FlamegraphData {
+    build_tree(name, sn, snd, None, opts)
+}
+
+// iterative, children are built before their parent (which then takes them out of built), so
+// deep stacks don't need a deep call stack.
+fn build_tree(name: String, sn: Vec<StackNode>, snd: Vec<StackNodeData>, deltas: Option<HashMap<i64, i64>>, opts: &TreeOptions) -> D3FlamegraphData {
+    let sd_map: HashMap<i64, StackNodeData> = HashMap::from_iter(snd);
+    let mut sn_p_id_map: HashMap<i64, Vec<&StackNode>> = HashMap::new();
+    let mut roots: Vec<&StackNode> = Vec::new();
+    for x in sn.iter() {
+        match x.parent_id {
+            Some(p) => sn_p_id_map.entry(p).or_default().push(x),
+            None => roots.push(x),
+        }
+    }
+    if let Some(root) = opts.root {
+        roots = sn.iter().filter(|x| x.id == root).collect();
+    }
+    let total: i64 = roots.iter().map(|x| x.sample_count).sum();
+    let min_value = opts.min_pct.map_or(0.0, |x| total as f64 * x / 100.0);
+    let kids_of = |cur: &StackNode, depth: usize| -> Vec<&StackNode> {
+        if opts.max_depth.map_or(false, |x| depth >= x) {
+            return Vec::new();
+        }
+        sn_p_id_map
+            .get(&cur.id)
+            .map(|x| x.iter().filter(|y| y.sample_count as f64 >= min_value).copied().collect())
+            .unwrap_or_default()
+    };
+
+    let mut built: HashMap<i64, D3FlamegraphData> = HashMap::new();
+    let mut stack: Vec<(&StackNode, usize, bool)> = roots
+        .iter()
+        .filter(|x| x.sample_count as f64 >= min_value)
+        .map(|x| (*x, 1, false))
+        .collect();
+    while let Some((cur, depth, expanded)) = stack.pop() {
+        let kids = kids_of(cur, depth);
+        if !expanded && !kids.is_empty() {
+            stack.push((cur, depth, true));
+            stack.extend(kids.iter().map(|x| (*x, depth + 1, false)));
+            continue;
+        }
+        // the frame can be missing (e.g. collected while this was being read), its samples
+        // still count so it's shown as unknown, same as in the exports.
+        let cur_sd = sd_map.get(&cur.stack_node_data_id);
+        let children = match kids.is_empty() {
+            true => None,
+            false => Some(kids.iter().filter_map(|x| built.remove(&x.id)).collect()),
+        };
+        built.insert(cur.id, D3FlamegraphData {
+            name: cur_sd.map_or_else(|| "[unknown]".to_string(), frame_name),
+            value: cur.sample_count,
+            filename: cur_sd.and_then(|x| x.file.clone()),
+            line_number: cur_sd.and_then(|x| x.line_number),
+            children,
+            delta: deltas.as_ref().map(|x| x.get(&cur.id).copied().unwrap_or(cur.sample_count)),
+            frame_id: cur_sd.map(|x| x.id),
+            node_id: deltas.is_none().then_some(cur.id),
+        });
+    }
+    let children: Vec<D3FlamegraphData> = roots.iter().filter_map(|x| built.remove(&x.id)).collect();
+    D3FlamegraphData {
+        name,
+        value: children.iter().map(|x| x.value).sum(),
+        filename: None,
+        line_number: None,
+        delta: deltas.as_ref().map(|_| children.iter().filter_map(|x| x.delta).sum()),
+        frame_id: None,
+        node_id: None,
+        children: Some(children),
+    }
+}
+
+// collapses nodes from any number of executables into one tree, where two nodes are the same if
+// they have the same frame (stack_node_data) at the end of the same path. counts are summed and
+// merged nodes get fresh ids, w/ executable_id set to 0 since they no longer belong to just one.
+pub fn merge_nodes(sn: Vec<StackNode>) -> Vec<StackNode> {
+    merge_into(&sn, &mut HashMap::new()).into_values().collect()
+}
+
+// merged_ids is (merged parent id, stack_node_data id) -> merged id, shared between calls so
+// separate sets of nodes can be merged into the same id space.
+fn merge_into(sn: &[StackNode], merged_ids: &mut HashMap<(Option<i64>, i64), i64>) -> HashMap<i64, StackNode> {
+    let mut sn_p_id_map: HashMap<i64, Vec<&StackNode>> = HashMap::new();
+    let mut roots: Vec<&StackNode> = Vec::new();
+    for x in sn.iter() {
+        match x.parent_id {
+            Some(p) => sn_p_id_map.entry(p).or_default().push(x),
+            None => roots.push(x),
+        }
+    }
+    merge_subtrees(roots, &sn_p_id_map, merged_ids)
+}
+
+// the subtrees under roots merged into one tree, w/ each root a root of the merged tree.
+fn merge_subtrees(
+    roots: Vec<&StackNode>,
+    sn_p_id_map: &HashMap<i64, Vec<&StackNode>>,
+    merged_ids: &mut HashMap<(Option<i64>, i64), i64>,
+) -> HashMap<i64, StackNode> {
+    let mut queue: Vec<(&StackNode, Option<i64>)> = roots.into_iter().map(|x| (x, None)).collect();
+    let mut merged: HashMap<i64, StackNode> = HashMap::new();
+    while let Some((cur, merged_parent)) = queue.pop() {
+        let next_id = merged_ids.len() as i64 + 1;
+        let merged_id = *merged_ids
+            .entry((merged_parent, cur.stack_node_data_id))
+            .or_insert(next_id);
+        merged
+            .entry(merged_id)
+            .and_modify(|e| e.sample_count += cur.sample_count)
+            .or_insert(StackNode {
+                id: merged_id,
+                parent_id: merged_parent,
+                stack_node_data_id: cur.stack_node_data_id,
+                executable_id: 0,
+                sample_count: cur.sample_count,
+            });
+        if let Some(children) = sn_p_id_map.get(&cur.id) {
+            queue.extend(children.iter().map(|x| (*x, Some(merged_id))));
+        }
+    }
+    merged
+}
+
+// butterfly view of the given frames (usually all stack_node_data rows of one symbol), as
+// (callers, callees) trees of merged nodes. callees is every subtree under the frames merged
+// together, callers is every path from the frames up to a root, merged the same way, so its
+// children are callers. only the outermost occurrence on a path counts, so recursion doesn't
+// double count.
+pub fn callgraph_nodes(sn: &[StackNode], frames: &HashSet<i64>) -> (Vec<StackNode>, Vec<StackNode>) {
+    let sn_id_map: HashMap<i64, &StackNode> = sn.iter().map(|x| (x.id, x)).collect();
+    let mut sn_p_id_map: HashMap<i64, Vec<&StackNode>> = HashMap::new();
+    let mut stack: Vec<(&StackNode, bool)> = Vec::new();
+    for x in sn.iter() {
+        match x.parent_id {
+            Some(p) => sn_p_id_map.entry(p).or_default().push(x),
+            None => stack.push((x, false)),
+        }
+    }
+    let mut occurrences: Vec<&StackNode> = Vec::new();
+    while let Some((cur, inside)) = stack.pop() {
+        let hit = !inside && frames.contains(&cur.stack_node_data_id);
+        if hit {
+            occurrences.push(cur);
+        }
+        if let Some(children) = sn_p_id_map.get(&cur.id) {
+            stack.extend(children.iter().map(|x| (*x, inside || hit)));
+        }
+    }
+
+    let callees = merge_subtrees(occurrences.clone(), &sn_p_id_map, &mut HashMap::new());
+
+    let mut merged_ids: HashMap<(Option<i64>, i64), i64> = HashMap::new();
+    let mut callers: HashMap<i64, StackNode> = HashMap::new();
+    for occurrence in occurrences {
+        let mut cur = Some(occurrence);
+        let mut merged_parent: Option<i64> = None;
+        while let Some(x) = cur {
+            let next_id = merged_ids.len() as i64 + 1;
+            let merged_id = *merged_ids
+                .entry((merged_parent, x.stack_node_data_id))
+                .or_insert(next_id);
+            callers
+                .entry(merged_id)
+                .and_modify(|e| e.sample_count += occurrence.sample_count)
+                .or_insert(StackNode {
+                    id: merged_id,
+                    parent_id: merged_parent,
+                    stack_node_data_id: x.stack_node_data_id,
+                    executable_id: 0,
+                    sample_count: occurrence.sample_count,
+                });
+            merged_parent = Some(merged_id);
+            cur = x.parent_id.and_then(|p| sn_id_map.get(&p).copied());
+        }
+    }
+    (callers.into_values().collect(), callees.into_values().collect())
+}
+
+// differential flamegraph of target against base, over every call path in either. values are
+// target's, each node gets a delta of target samples minus base samples on the same call path, w/
+// base scaled up or down to target's total first so profiles of different lengths compare. paths
+// only in base are kept at a value of 0, so what went away shows up as a negative delta.
+pub fn diff_flamegraph(name: String, base: Vec<StackNode>, target: Vec<StackNode>, snd: Vec<StackNodeData>) -> D3FlamegraphData {
+    let mut merged_ids = HashMap::new();
+    let base = merge_into(&base, &mut merged_ids);
+    let mut target = merge_into(&target, &mut merged_ids);
+    let total = |x: &HashMap<i64, StackNode>| -> i64 {
+        x.values().filter(|y| y.parent_id.is_none()).map(|y| y.sample_count).sum()
+    };
+    let (base_total, target_total) = (total(&base), total(&target));
+    let scale = match base_total {
+        0 => 0.0,
+        x => target_total as f64 / x as f64,
+    };
+    // merged ids are shared, so a base only node's parent is either in target or base only too.
+    for x in base.values() {
+        target.entry(x.id).or_insert(StackNode { sample_count: 0, ..x.clone() });
+    }
+    let deltas: HashMap<i64, i64> = target
+        .values()
+        .map(|x| {
+            let base_count = base.get(&x.id).map_or(0, |y| y.sample_count);
+            (x.id, x.sample_count - (base_count as f64 * scale).round() as i64)
+        })
+        .collect();
+    build_tree(name, target.into_values().collect(), snd, Some(deltas), &TreeOptions::default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(id: i64, parent_id: Option<i64>, stack_node_data_id: i64, sample_count: i64) -> StackNode {
+        StackNode {
+            id,
+            parent_id,
+            stack_node_data_id,
+            executable_id: 1,
+            sample_count,
+        }
+    }
+
+    fn frame(id: i64, symbol: &str) -> StackNodeData {
+        StackNodeData {
+            id,
+            symbol: symbol.to_string(),
+            file: None,
+            line_number: None,
+        }
+    }
+
+    fn child<'a>(x: &'a D3FlamegraphData, name: &str) -> &'a D3FlamegraphData {
+        x.children.iter().flatten().find(|y| y.name == name).unwrap()
+    }
+
+    #[test]
+    fn missing_frames_are_unknown() {
+        let sn = |id, parent_id, stack_node_data_id| node(id, parent_id, stack_node_data_id, 3);
+        let snd = vec![frame(100, "main")];
+        // 200 isn't there.
+        let tree = build_flamegraph("app".to_string(), vec![sn(1, None, 100), sn(2, Some(1), 200)], snd);
+        assert_eq!(tree.value, 3);
+        let main = &tree.children.as_ref().unwrap()[0];
+        assert_eq!((main.name.as_str(), main.frame_id), ("main", Some(100)));
+        let unknown = &main.children.as_ref().unwrap()[0];
+        assert_eq!((unknown.name.as_str(), unknown.value, unknown.frame_id), ("[unknown]", 3, None));
+    }
+
+    #[test]
+    fn diffs_cover_both_sides() {
+        let snd = vec![frame(1, "main"), frame(2, "parse"), frame(3, "old"), frame(4, "new"), frame(5, "inner")];
+        // base: main 100 (parse 50, old 50 (inner 50)). target is twice as long: main 200
+        // (parse 100, new 100). parse scaled, old (and inner below it) gone, new added.
+        let base = vec![node(10, None, 1, 100), node(11, Some(10), 2, 50), node(12, Some(10), 3, 50), node(13, Some(12), 5, 50)];
+        let target = vec![node(20, None, 1, 200), node(21, Some(20), 2, 100), node(22, Some(20), 4, 100)];
+        let tree = diff_flamegraph("app".to_string(), base, target, snd);
+
+        assert_eq!((tree.value, tree.delta), (200, Some(0)));
+        let main = child(&tree, "main");
+        assert_eq!((main.value, main.delta, main.node_id), (200, Some(0), None));
+        let parse = child(main, "parse");
+        assert_eq!((parse.value, parse.delta), (100, Some(0)));
+        let new = child(main, "new");
+        assert_eq!((new.value, new.delta), (100, Some(100)));
+        let old = child(main, "old");
+        assert_eq!((old.value, old.delta), (0, Some(-100)));
+        let inner = child(old, "inner");
+        assert_eq!((inner.value, inner.delta), (0, Some(-100)));
+    }
+}
@@ -1,17 +1,20 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use crate::bpftune::bpftune_bss_types::stacktrace_event;
 use blazesym::SymbolizedResult;
 use chrono::{DateTime, Utc};
 use clap::{arg, command};
-use clap::{Parser, ValueEnum};
+use clap::{Parser, Subcommand, ValueEnum};
 use dashmap::DashMap;
 use highway::Key;
 use once_cell::sync::Lazy;
 use serde_derive::{Deserialize, Serialize};
 use sqlx::FromRow;
 
+use std::path::PathBuf;
 use std::sync::Arc;
 
+use crate::wire::{WireCompression, WireFormat};
+
 #[macro_use]
 use enum_display_derive;
 use deepsize::DeepSizeOf;
@@ -21,10 +24,26 @@ pub const READ_TASK_COUNT: usize = 10000000;
 pub const PROCESS_TASK_COUNT: usize = 100;
 pub const WORKER_COUNT: usize = 4;
 
+// bump PROTOCOL_VERSION whenever StoData (or anything in it) changes shape, and keep
+// MIN_PROTOCOL_VERSION at the oldest version the server still knows how to ingest.
+// v1 payloads predate the version field entirely.
+pub const PROTOCOL_VERSION: u32 = 4;
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+// default for how many frames the server stores before acking a stream.
+pub const STREAM_ACK_EVERY: u64 = 16;
+
 pub type ReadQueue = deadqueue::limited::Queue<StackInfo>;
 pub type ProcessQueue = deadqueue::limited::Queue<Vec<Vec<SymbolizedResult>>>;
 
 pub const HASHER_SEED: Key = Key([1, 2, 3, 4]);
+
+// ids are postgres bigints, so 64 bit hashes get folded into the non-negative half. this is
+// the same as the old `(hash as i64).abs()` (so existing ids stay put) except that i64::MIN,
+// which has no positive counterpart and used to overflow, lands on i64::MAX.
+pub fn hash_to_id(hash: u64) -> i64 {
+    (hash as i64).checked_abs().unwrap_or(i64::MAX)
+}
 pub static NODES: Lazy<Arc<DashMap<i64, StackNode>>> = Lazy::new(|| Arc::new(DashMap::new()));
 pub static DATAS: Lazy<Arc<DashMap<i64, StackNodeData>>> = Lazy::new(|| Arc::new(DashMap::new()));
 pub static BINARIES: Lazy<Arc<DashMap<i64, Executable>>> =
@@ -36,8 +55,24 @@ pub enum EventType {
     Clock,
 }
 
-#[clap(disable_version_flag = true)]
+// where the cli puts what it collected. anything but server is written once profiling is done.
+#[derive(
+    ValueEnum, Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, enum_display_derive::Display,
+)]
+pub enum OutputFormat {
+    Server,
+    Folded,
+    Speedscope,
+    Chrome,
+    // StoData as is, for `upload` later. sto is the header'd container w/ --format and
+    // --compression, json and msgpack are plain.
+    Sto,
+    Json,
+    Msgpack,
+}
+
 #[derive(Parser, Debug, Serialize, Deserialize, Clone)]
+#[clap(disable_version_flag = true)]
 #[command(author, version, about, long_about = "Do stuff")]
 pub struct Args {
     #[arg(
@@ -61,16 +96,245 @@ pub struct Args {
         short,
         long,
         help = "write data to the specified url",
-        default_value = "http://localhost:8000/data/samples"
+        default_value = "http://localhost:8000/api/v1/data/samples"
     )]
     pub url: String,
+    #[arg(value_enum, long, default_value_t = WireFormat::Msgpack, help = "serialization used when uploading.")]
+    pub format: WireFormat,
+    #[arg(value_enum, long, default_value_t = WireCompression::Zstd, help = "compression used when uploading.")]
+    pub compression: WireCompression,
+    #[arg(long, help = "send batches over one long lived connection instead of a post each.")]
+    pub stream: bool,
+    #[arg(
+        short,
+        long = "label",
+        value_parser = parse_label,
+        help = "key=value label for this profile, can be given more than once."
+    )]
+    pub labels: Vec<(String, String)>,
+    #[arg(long, help = "label profiles w/ this host's hostname and kernel. labels are part of what identifies an executable, so each host's profiles are stored apart.")]
+    pub host_labels: bool,
+    #[arg(value_enum, long, default_value_t = OutputFormat::Server, help = "upload to the server or write a file in another format.")]
+    pub output: OutputFormat,
+    #[arg(long, help = "where to write --output other than server, stdout if not given. w/o --output, profiles go here as a sto file to upload later.")]
+    pub output_file: Option<PathBuf>,
+    #[arg(long, help = "include file:line in frame names, for formats that only have names.")]
+    pub lines: bool,
+    #[arg(long, help = "stop profiling after this many seconds, otherwise it runs until ctrl-c.")]
+    pub duration: Option<u64>,
+    #[arg(skip)]
+    pub window_start: Option<DateTime<Utc>>,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug, Serialize, Deserialize, Clone)]
+pub enum Command {
+    #[command(about = "run as a daemon, periodically profiling whatever matches the config.")]
+    Agent {
+        #[arg(short, long, default_value = "/etc/sto/agent.toml")]
+        config: PathBuf,
+    },
+    #[command(about = "look at a profile written w/ --output sto, json or msgpack in the browser, no server setup needed.")]
+    View {
+        file: PathBuf,
+    },
+    #[command(about = "upload a profile written w/ --output sto, json or msgpack.")]
+    Upload {
+        file: PathBuf,
+    },
+    #[command(about = "convert a profile from another tool, then upload it (or --output it).")]
+    Import {
+        #[command(subcommand)]
+        kind: ImportKind,
+    },
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone, DeepSizeOf)]
+#[derive(Subcommand, Debug, Serialize, Deserialize, Clone)]
+pub enum ImportKind {
+    #[command(about = "output of perf script on a perf record -g profile, - for stdin.")]
+    PerfScript {
+        file: PathBuf,
+        #[arg(long, help = "only import samples of this event, the first one seen otherwise.")]
+        event: Option<String>,
+    },
+    #[command(about = "pprof protobuf, gzipped or not, - for stdin.")]
+    Pprof {
+        file: PathBuf,
+        #[arg(long, help = "sample type to import (type or type/unit), the profile's default otherwise.")]
+        sample_type: Option<String>,
+        #[arg(long, help = "keep the profile's labels w/ this key (repeatable), each distinct set is stored as its own executable. none are kept by default.")]
+        label_key: Vec<String>,
+    },
+    #[command(about = "collapsed stacks, one `root;...;leaf count` per line, - for stdin.")]
+    Folded {
+        file: PathBuf,
+        #[arg(long, default_value = "samples", help = "what to call the event the counts are of.")]
+        event: String,
+    },
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default, DeepSizeOf)]
 pub struct StoData {
+    #[serde(default = "legacy_protocol_version")]
+    pub version: u32,
+    // when the samples were taken, if the client knows (v3+).
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub timestamp: Option<DateTime<Utc>>,
     pub stack_nodes: Vec<StackNode>,
     pub stack_node_datas: Vec<StackNodeData>,
     pub profiled_binaries: Vec<Executable>,
+    // v4+.
+    #[serde(default)]
+    pub executable_labels: Vec<ExecutableLabel>,
+}
+
+fn legacy_protocol_version() -> u32 {
+    1
+}
+
+impl StoData {
+    pub fn check_version(&self) -> Result<(), String> {
+        match self.version {
+            MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION => Ok(()),
+            x => Err(format!(
+                "unsupported protocol version {}, server accepts {} through {}",
+                x, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+            )),
+        }
+    }
+
+    // executable sample_count counts frames, every sample has exactly one root node though.
+    pub fn samples(&self) -> i64 {
+        self.stack_nodes.iter().filter(|x| x.parent_id.is_none()).map(|x| x.sample_count).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.stack_nodes.is_empty()
+            && self.stack_node_datas.is_empty()
+            && self.profiled_binaries.is_empty()
+    }
+
+    // same semantics as ingest: counts and sizes add up, everything else is keyed by id.
+    pub fn merge(&mut self, other: StoData) {
+        self.version = self.version.max(other.version);
+        self.timestamp = self.timestamp.or(other.timestamp);
+        let mut nodes: HashMap<i64, StackNode> =
+            self.stack_nodes.drain(..).map(|x| (x.id, x)).collect();
+        for node in other.stack_nodes {
+            nodes
+                .entry(node.id)
+                .and_modify(|e| e.sample_count += node.sample_count)
+                .or_insert(node);
+        }
+        let mut datas: HashMap<i64, StackNodeData> = self.stack_node_datas.drain(..).collect();
+        for data in other.stack_node_datas {
+            datas.entry(data.id).or_insert(data);
+        }
+        let mut executables: HashMap<i64, Executable> = self.profiled_binaries.drain(..).collect();
+        for executable in other.profiled_binaries {
+            executables
+                .entry(executable.id)
+                .and_modify(|e| {
+                    e.sample_count += executable.sample_count;
+                    e.raw_data_size += executable.raw_data_size;
+                    e.processed_data_size += executable.processed_data_size;
+                })
+                .or_insert(executable);
+        }
+        for label in other.executable_labels {
+            if !self.executable_labels.contains(&label) {
+                self.executable_labels.push(label);
+            }
+        }
+        self.stack_nodes = nodes.into_values().collect();
+        self.stack_node_datas = datas.into_values().collect();
+        self.profiled_binaries = executables.into_values().collect();
+    }
+}
+
+// sent back over /data/stream every so often, and once more when the stream ends.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct StreamAck {
+    pub frames: u64,
+    pub samples: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+// what /api/version hands back so clients can pick something the server understands.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ServerInfo {
+    pub protocol_version: u32,
+    pub min_protocol_version: u32,
+    pub formats: Vec<WireFormat>,
+    pub compressions: Vec<WireCompression>,
+    #[serde(default)]
+    pub streaming: bool,
+}
+
+impl ServerInfo {
+    pub fn current() -> ServerInfo {
+        ServerInfo {
+            protocol_version: PROTOCOL_VERSION,
+            min_protocol_version: MIN_PROTOCOL_VERSION,
+            formats: vec![WireFormat::Json, WireFormat::Msgpack],
+            compressions: vec![
+                WireCompression::None,
+                WireCompression::Gzip,
+                WireCompression::Zstd,
+            ],
+            streaming: true,
+        }
+    }
+
+    pub fn supports_protocol(&self, version: u32) -> bool {
+        (self.min_protocol_version..=self.protocol_version).contains(&version)
+    }
+
+    // prefer what was asked for, fall back to plain json if the server doesn't know it.
+    pub fn negotiate(
+        &self,
+        format: WireFormat,
+        compression: WireCompression,
+    ) -> (WireFormat, WireCompression) {
+        let format = match self.formats.contains(&format) {
+            true => format,
+            false => WireFormat::Json,
+        };
+        let compression = match self.compressions.contains(&compression) {
+            true => compression,
+            false => WireCompression::None,
+        };
+        (format, compression)
+    }
+}
+
+// two different rows that hashed to the same id. the stored row wins and the incoming one is
+// recorded here rather than silently sharing its samples.
+#[derive(Debug, Serialize, Deserialize, Clone, FromRow)]
+pub struct HashCollision {
+    pub id: i64,
+    pub kind: String,
+    pub hashed_id: i64,
+    pub stored: serde_json::Value,
+    pub incoming: serde_json::Value,
+    pub seen_at: DateTime<Utc>,
+}
+
+// a row of findRegressions, a symbol that takes up a bigger share of target's samples than base's.
+#[derive(Debug, Serialize, Deserialize, Clone, FromRow)]
+pub struct Regression {
+    pub basename: String,
+    pub base_id: i64,
+    pub base_build_id: Option<String>,
+    pub target_id: i64,
+    pub target_build_id: Option<String>,
+    pub file: Option<String>,
+    pub symbol: String,
+    pub base_samples: i64,
+    pub target_samples: i64,
+    pub pct_diff: f64,
 }
 
 #[derive(Debug, Clone)]
@@ -95,6 +359,31 @@ pub struct Executable {
     pub processed_data_size: i64,
 }
 
+// free form metadata (hostname, region, git sha, ...). labels are part of what identifies an
+// executable, so the same build on two hosts is two executables that can be merged back up.
+#[derive(Debug, Serialize, Deserialize, Clone, FromRow, Hash, Eq, PartialEq, DeepSizeOf)]
+pub struct ExecutableLabel {
+    pub executable_id: i64,
+    pub key: String,
+    pub value: String,
+}
+
+// an executable w/ its labels, as listed by /executables.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct LabeledExecutable {
+    #[serde(flatten)]
+    pub executable: Executable,
+    pub labels: BTreeMap<String, String>,
+}
+
+// `key=value`, as taken by --label and the label= query param.
+pub fn parse_label(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((k, v)) if !k.trim().is_empty() => Ok((k.trim().to_string(), v.trim().to_string())),
+        _ => Err(format!("expected key=value, got {}", s)),
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone, FromRow, Hash, Eq, PartialEq, DeepSizeOf)]
 pub struct StackNode {
     pub id: i64,
@@ -0,0 +1,481 @@
+use anyhow::Result;
+use flate2::write::GzEncoder;
+use inferno::flamegraph::{self, color::Color, color::PaletteMap};
+use prost::Message;
+use serde_json::json;
+use std::collections::{BTreeMap, HashMap};
+use std::io::Write;
+
+use crate::dag::frame_name;
+use crate::defs::{Executable, StackNode, StackNodeData};
+
+// stored profiles in formats other tools understand. everything here works off the same rows
+// the server stores and the cli sends, so both can export.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    // gzipped pprof protobuf, for go tool pprof and friends.
+    Pprof,
+    // brendan gregg's collapsed stacks, for flamegraph.pl, inferno, etc.
+    Folded,
+    // https://www.speedscope.app sampled profile.
+    Speedscope,
+    // chrome trace events, for chrome://tracing, perfetto, etc.
+    ChromeTrace,
+}
+
+impl ExportFormat {
+    // by what a file name ends w/, e.g. 12.pb.gz.
+    pub fn from_extension(ext: &str) -> Option<ExportFormat> {
+        match ext {
+            "pb.gz" => Some(ExportFormat::Pprof),
+            "folded" => Some(ExportFormat::Folded),
+            "speedscope.json" => Some(ExportFormat::Speedscope),
+            "trace.json" => Some(ExportFormat::ChromeTrace),
+            _ => None,
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            ExportFormat::Pprof => "application/octet-stream",
+            ExportFormat::Folded => "text/plain; charset=utf-8",
+            ExportFormat::Speedscope | ExportFormat::ChromeTrace => "application/json",
+        }
+    }
+}
+
+// self samples of every node (inclusive count minus its children's), w/ the path of frames
+// from it up to the root, leaf first.
+fn leaf_stacks(sn: &[StackNode]) -> Vec<(Vec<i64>, i64)> {
+    let sn_id_map: HashMap<i64, &StackNode> = sn.iter().map(|x| (x.id, x)).collect();
+    let mut child_counts: HashMap<i64, i64> = HashMap::new();
+    for x in sn.iter() {
+        if let Some(p) = x.parent_id {
+            *child_counts.entry(p).or_default() += x.sample_count;
+        }
+    }
+    sn.iter()
+        .filter_map(|x| {
+            let own = x.sample_count - child_counts.get(&x.id).copied().unwrap_or(0);
+            if own <= 0 {
+                return None;
+            }
+            let mut frames = Vec::new();
+            let mut cur = Some(x);
+            while let Some(y) = cur {
+                frames.push(y.stack_node_data_id);
+                cur = y.parent_id.and_then(|p| sn_id_map.get(&p).copied());
+            }
+            Some((frames, own))
+        })
+        .collect()
+}
+
+// `root;...;leaf count` lines, sorted. w/ lines, frames are named like /dag names them
+// (symbol:file:line), otherwise stacks that only differ in file or line get added up.
+pub fn folded(sn: &[StackNode], snd: &[StackNodeData], lines: bool) -> String {
+    let names: HashMap<i64, String> = snd
+        .iter()
+        .map(|x| {
+            let name = match lines {
+                true => frame_name(x),
+                false => x.symbol.clone(),
+            };
+            (x.id, name)
+        })
+        .collect();
+    let mut stacks: BTreeMap<String, i64> = BTreeMap::new();
+    for (frames, count) in leaf_stacks(sn) {
+        let stack: Vec<&str> = frames
+            .iter()
+            .rev()
+            .map(|x| names.get(x).map(|y| y.as_str()).unwrap_or("[unknown]"))
+            .collect();
+        *stacks.entry(stack.join(";")).or_default() += count;
+    }
+    stacks
+        .iter()
+        .map(|(stack, count)| format!("{} {}\n", stack, count))
+        .collect()
+}
+
+// the frame table is just the stack_node_data rows, every leaf stack is a sample weighted by
+// its count.
+pub fn speedscope(name: &str, sn: &[StackNode], snd: &[StackNodeData]) -> String {
+    let frame_idx: HashMap<i64, usize> = snd.iter().enumerate().map(|(i, x)| (x.id, i)).collect();
+    let frames: Vec<serde_json::Value> = snd
+        .iter()
+        .map(|x| json!({"name": x.symbol, "file": x.file, "line": x.line_number}))
+        .collect();
+    let (samples, weights): (Vec<Vec<usize>>, Vec<i64>) = leaf_stacks(sn)
+        .into_iter()
+        .map(|(frames, count)| {
+            let stack = frames.iter().rev().filter_map(|x| frame_idx.get(x).copied()).collect();
+            (stack, count)
+        })
+        .unzip();
+    json!({
+        "$schema": "https://www.speedscope.app/file-format-schema.json",
+        "exporter": "sto",
+        "name": name,
+        "activeProfileIndex": 0,
+        "shared": {"frames": frames},
+        "profiles": [{
+            "type": "sampled",
+            "name": name,
+            "unit": "none",
+            "startValue": 0,
+            "endValue": weights.iter().sum::<i64>(),
+            "samples": samples,
+            "weights": weights,
+        }],
+    })
+    .to_string()
+}
+
+// there's no timing in a stored profile, so this lays the tree out like a flame chart: each
+// node is a complete event as long as its sample count (1 sample = 1us), children back to back.
+pub fn chrome_trace(name: &str, sn: &[StackNode], snd: &[StackNodeData]) -> String {
+    let sd_map: HashMap<i64, &StackNodeData> = snd.iter().map(|x| (x.id, x)).collect();
+    let mut sn_p_id_map: HashMap<i64, Vec<&StackNode>> = HashMap::new();
+    let mut roots: Vec<&StackNode> = Vec::new();
+    for x in sn.iter() {
+        match x.parent_id {
+            Some(p) => sn_p_id_map.entry(p).or_default().push(x),
+            None => roots.push(x),
+        }
+    }
+    let name_of = |x: &StackNode| sd_map.get(&x.stack_node_data_id).map_or("[unknown]", |y| y.symbol.as_str());
+    roots.sort_by(|a, b| name_of(a).cmp(name_of(b)));
+    for children in sn_p_id_map.values_mut() {
+        children.sort_by(|a, b| name_of(a).cmp(name_of(b)));
+    }
+    // (node, start) of nodes laid out back to back from start.
+    fn lay_out<'a>(nodes: &[&'a StackNode], start: i64) -> Vec<(&'a StackNode, i64)> {
+        let mut offset = start;
+        nodes
+            .iter()
+            .map(|x| {
+                let out = (*x, offset);
+                offset += x.sample_count;
+                out
+            })
+            .collect()
+    }
+    let mut events: Vec<serde_json::Value> = vec![json!({
+        "name": "thread_name", "ph": "M", "pid": 1, "tid": 1, "args": {"name": name},
+    })];
+    let mut stack = lay_out(&roots, 0);
+    while let Some((cur, start)) = stack.pop() {
+        let sd = sd_map.get(&cur.stack_node_data_id);
+        events.push(json!({
+            "name": name_of(cur),
+            "cat": "sto",
+            "ph": "X",
+            "ts": start,
+            "dur": cur.sample_count,
+            "pid": 1,
+            "tid": 1,
+            "args": {"file": sd.and_then(|x| x.file.clone()), "line": sd.and_then(|x| x.line_number)},
+        }));
+        if let Some(children) = sn_p_id_map.get(&cur.id) {
+            stack.extend(lay_out(children, start));
+        }
+    }
+    json!({"traceEvents": events, "displayTimeUnit": "ms"}).to_string()
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SvgOptions {
+    pub title: Option<String>,
+    // of the whole image, in pixels.
+    pub width: Option<usize>,
+    // frames narrower than this (in pixels) are left out.
+    pub min_width: Option<f64>,
+    // frames w/ this in their name are drawn in the search color up front, so it shows w/o js
+    // (e.g. in an email). the svg's own search (ctrl-f, or ?s= on its url) works too.
+    pub search: Option<String>,
+    // file:line in frame names, like folded.
+    pub lines: bool,
+}
+
+// a standalone interactive svg (zoom, search, tooltips), drawn by inferno off the folded stacks.
+pub fn svg(sn: &[StackNode], snd: &[StackNodeData], opts: &SvgOptions) -> Result<Vec<u8>> {
+    let stacks = folded(sn, snd, opts.lines);
+    let mut palette_map = PaletteMap::default();
+    if let Some(term) = opts.search.as_ref().filter(|x| !x.is_empty()) {
+        let search_color = Color { r: 230, g: 0, b: 230 };
+        for line in stacks.lines() {
+            let frames = line.rsplit_once(' ').map_or(line, |(x, _)| x);
+            for frame in frames.split(';').filter(|x| x.contains(term.as_str())) {
+                palette_map.insert(frame, search_color);
+            }
+        }
+    }
+    let mut options = flamegraph::Options::default();
+    if let Some(x) = opts.title.as_ref() {
+        options.title = x.clone();
+    }
+    options.image_width = opts.width;
+    if let Some(x) = opts.min_width {
+        options.min_width = x;
+    }
+    options.count_name = "samples".to_string();
+    options.palette_map = Some(&mut palette_map);
+    let mut out = Vec::new();
+    flamegraph::from_lines(&mut options, stacks.lines(), &mut out)?;
+    Ok(out)
+}
+
+pub fn to_pprof(
+    executable: &Executable,
+    labels: &BTreeMap<String, String>,
+    sn: &[StackNode],
+    snd: &[StackNodeData],
+) -> pprof::Profile {
+    let mut strings = StringTable::default();
+    let sample_type = pprof::ValueType {
+        r#type: strings.get(&executable.event.to_lowercase()),
+        unit: strings.get("count"),
+    };
+    let mapping = pprof::Mapping {
+        id: 1,
+        filename: strings.get(&executable.basename),
+        build_id: strings.get(executable.build_id.as_deref().unwrap_or_default()),
+        has_functions: true,
+        has_filenames: snd.iter().any(|x| x.file.is_some()),
+        has_line_numbers: snd.iter().any(|x| x.line_number.is_some()),
+        ..Default::default()
+    };
+
+    // a function per symbol+file, a location per stack_node_data row (so per line).
+    let mut functions: Vec<pprof::Function> = Vec::new();
+    let mut function_ids: HashMap<(&str, Option<&str>), u64> = HashMap::new();
+    let mut locations: Vec<pprof::Location> = Vec::new();
+    let mut location_ids: HashMap<i64, u64> = HashMap::new();
+    for sd in snd.iter() {
+        let next_id = functions.len() as u64 + 1;
+        let function_id = *function_ids
+            .entry((sd.symbol.as_str(), sd.file.as_deref()))
+            .or_insert_with(|| {
+                functions.push(pprof::Function {
+                    id: next_id,
+                    name: strings.get(&sd.symbol),
+                    system_name: strings.get(&sd.symbol),
+                    filename: strings.get(sd.file.as_deref().unwrap_or_default()),
+                    start_line: 0,
+                });
+                next_id
+            });
+        let location_id = locations.len() as u64 + 1;
+        locations.push(pprof::Location {
+            id: location_id,
+            mapping_id: 1,
+            line: vec![pprof::Line {
+                function_id,
+                line: sd.line_number.unwrap_or_default() as i64,
+            }],
+            ..Default::default()
+        });
+        location_ids.insert(sd.id, location_id);
+    }
+
+    let label: Vec<pprof::Label> = labels
+        .iter()
+        .map(|(k, v)| pprof::Label {
+            key: strings.get(k),
+            str: strings.get(v),
+            ..Default::default()
+        })
+        .collect();
+    let sample = leaf_stacks(sn)
+        .into_iter()
+        .map(|(frames, count)| pprof::Sample {
+            location_id: frames.iter().filter_map(|x| location_ids.get(x).copied()).collect(),
+            value: vec![count],
+            label: label.clone(),
+        })
+        .collect();
+
+    pprof::Profile {
+        sample_type: vec![sample_type.clone()],
+        sample,
+        mapping: vec![mapping],
+        location: locations,
+        function: functions,
+        time_nanos: executable
+            .created_at
+            .and_then(|x| x.timestamp_nanos_opt())
+            .unwrap_or_default(),
+        period_type: Some(sample_type),
+        period: 1,
+        string_table: strings.strings,
+        ..Default::default()
+    }
+}
+
+pub fn pprof_gz(
+    executable: &Executable,
+    labels: &BTreeMap<String, String>,
+    sn: &[StackNode],
+    snd: &[StackNodeData],
+) -> Result<Vec<u8>> {
+    let profile = to_pprof(executable, labels, sn, snd);
+    let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+    encoder.write_all(&profile.encode_to_vec())?;
+    Ok(encoder.finish()?)
+}
+
+// pprof wants every string as an index into one table, w/ "" at 0.
+struct StringTable {
+    strings: Vec<String>,
+    index: HashMap<String, i64>,
+}
+
+impl Default for StringTable {
+    fn default() -> Self {
+        StringTable {
+            strings: vec![String::new()],
+            index: HashMap::from([(String::new(), 0)]),
+        }
+    }
+}
+
+impl StringTable {
+    fn get(&mut self, s: &str) -> i64 {
+        if let Some(x) = self.index.get(s) {
+            return *x;
+        }
+        let idx = self.strings.len() as i64;
+        self.strings.push(s.to_string());
+        self.index.insert(s.to_string(), idx);
+        idx
+    }
+}
+
+// the parts of https://github.com/google/pprof/blob/main/proto/profile.proto used here, by hand
+// so there's no protoc step in the build.
+pub mod pprof {
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Profile {
+        #[prost(message, repeated, tag = "1")]
+        pub sample_type: Vec<ValueType>,
+        #[prost(message, repeated, tag = "2")]
+        pub sample: Vec<Sample>,
+        #[prost(message, repeated, tag = "3")]
+        pub mapping: Vec<Mapping>,
+        #[prost(message, repeated, tag = "4")]
+        pub location: Vec<Location>,
+        #[prost(message, repeated, tag = "5")]
+        pub function: Vec<Function>,
+        #[prost(string, repeated, tag = "6")]
+        pub string_table: Vec<String>,
+        #[prost(int64, tag = "7")]
+        pub drop_frames: i64,
+        #[prost(int64, tag = "8")]
+        pub keep_frames: i64,
+        #[prost(int64, tag = "9")]
+        pub time_nanos: i64,
+        #[prost(int64, tag = "10")]
+        pub duration_nanos: i64,
+        #[prost(message, optional, tag = "11")]
+        pub period_type: Option<ValueType>,
+        #[prost(int64, tag = "12")]
+        pub period: i64,
+        #[prost(int64, repeated, tag = "13")]
+        pub comment: Vec<i64>,
+        #[prost(int64, tag = "14")]
+        pub default_sample_type: i64,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct ValueType {
+        #[prost(int64, tag = "1")]
+        pub r#type: i64,
+        #[prost(int64, tag = "2")]
+        pub unit: i64,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Sample {
+        #[prost(uint64, repeated, tag = "1")]
+        pub location_id: Vec<u64>,
+        #[prost(int64, repeated, tag = "2")]
+        pub value: Vec<i64>,
+        #[prost(message, repeated, tag = "3")]
+        pub label: Vec<Label>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Label {
+        #[prost(int64, tag = "1")]
+        pub key: i64,
+        #[prost(int64, tag = "2")]
+        pub str: i64,
+        #[prost(int64, tag = "3")]
+        pub num: i64,
+        #[prost(int64, tag = "4")]
+        pub num_unit: i64,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Mapping {
+        #[prost(uint64, tag = "1")]
+        pub id: u64,
+        #[prost(uint64, tag = "2")]
+        pub memory_start: u64,
+        #[prost(uint64, tag = "3")]
+        pub memory_limit: u64,
+        #[prost(uint64, tag = "4")]
+        pub file_offset: u64,
+        #[prost(int64, tag = "5")]
+        pub filename: i64,
+        #[prost(int64, tag = "6")]
+        pub build_id: i64,
+        #[prost(bool, tag = "7")]
+        pub has_functions: bool,
+        #[prost(bool, tag = "8")]
+        pub has_filenames: bool,
+        #[prost(bool, tag = "9")]
+        pub has_line_numbers: bool,
+        #[prost(bool, tag = "10")]
+        pub has_inline_frames: bool,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Location {
+        #[prost(uint64, tag = "1")]
+        pub id: u64,
+        #[prost(uint64, tag = "2")]
+        pub mapping_id: u64,
+        #[prost(uint64, tag = "3")]
+        pub address: u64,
+        #[prost(message, repeated, tag = "4")]
+        pub line: Vec<Line>,
+        #[prost(bool, tag = "5")]
+        pub is_folded: bool,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Line {
+        #[prost(uint64, tag = "1")]
+        pub function_id: u64,
+        #[prost(int64, tag = "2")]
+        pub line: i64,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Function {
+        #[prost(uint64, tag = "1")]
+        pub id: u64,
+        #[prost(int64, tag = "2")]
+        pub name: i64,
+        #[prost(int64, tag = "3")]
+        pub system_name: i64,
+        #[prost(int64, tag = "4")]
+        pub filename: i64,
+        #[prost(int64, tag = "5")]
+        pub start_line: i64,
+    }
+}
@@ -0,0 +1,888 @@
+use anyhow::{bail, Result};
+use chrono::{DateTime, TimeZone, Utc};
+use clap::ValueEnum;
+use flate2::read::GzDecoder;
+use highway::{HighwayHash, HighwayHasher};
+use moka::sync::Cache;
+use once_cell::sync::Lazy;
+use prost::Message;
+use std::collections::{BTreeMap, HashMap};
+use std::io::Read;
+use symbolic_demangle::{Demangle, DemangleOptions};
+
+use crate::defs::{
+    hash_to_id, Executable, ExecutableLabel, StackNode, StackNodeData, StoData, HASHER_SEED,
+    PROTOCOL_VERSION,
+};
+use crate::export::pprof;
+
+// turns stacks from wherever (the profiler, perf script, pprof, ...) into StoData. ids are hashes of
+// the content so the same frame/stack lands on the same row no matter where it came from.
+
+static SYM_CACHE: Lazy<Cache<String, String, ahash::RandomState>> = Lazy::new(|| {
+    Cache::builder()
+        .weigher(|key: &String, _value: &String| -> u32 {
+            key.len().try_into().unwrap_or(u32::MAX)
+        })
+        .max_capacity(32 * 1024 * 1024)
+        .build_with_hasher(ahash::RandomState::default())
+});
+
+static DATA_ID_CACHE: Lazy<Cache<StackNodeData, i64, ahash::RandomState>> = Lazy::new(|| {
+    Cache::builder()
+        .weigher(|key: &StackNodeData, _value: &i64| -> u32 {
+            key.symbol.len().try_into().unwrap_or(u32::MAX)
+                + key.file.as_ref().map_or(0, |x| x.len()).try_into().unwrap_or(u32::MAX)
+        })
+        .max_capacity(32 * 1024 * 1024)
+        .build_with_hasher(ahash::RandomState::default())
+});
+
+static NODE_ID_CACHE: Lazy<Cache<StackNode, i64, ahash::RandomState>> = Lazy::new(|| {
+    Cache::builder()
+        .max_capacity(8 * 1024 * 1024)
+        .build_with_hasher(ahash::RandomState::default())
+});
+
+static MISC_ID_CACHE: Lazy<Cache<String, i64, ahash::RandomState>> = Lazy::new(|| {
+    Cache::builder()
+        .weigher(|key: &String, _value: &i64| -> u32 { key.len().try_into().unwrap_or(u32::MAX) })
+        .max_capacity(2 * 1024 * 1024)
+        .build_with_hasher(ahash::RandomState::default())
+});
+
+pub fn cached_demangle(mangled: &str) -> String {
+    match SYM_CACHE.get(mangled) {
+        Some(hit) => hit,
+        None => {
+            let name = symbolic_common::Name::from(mangled);
+            let demangled = name.try_demangle(DemangleOptions::name_only());
+            SYM_CACHE.insert(mangled.into(), demangled.clone().into());
+            demangled.into()
+        }
+    }
+}
+
+pub fn misc_id(data: String) -> i64 {
+    match MISC_ID_CACHE.get(&data) {
+        Some(x) => x,
+        None => {
+            let mut hasher = HighwayHasher::new(HASHER_SEED);
+            hasher.append(data.as_bytes());
+            let id = hash_to_id(hasher.finalize64());
+            MISC_ID_CACHE.insert(data.clone(), id);
+            id
+        }
+    }
+}
+
+pub fn id_stack_node(data: &mut StackNode) {
+    let id = match NODE_ID_CACHE.get(data) {
+        Some(hit) => hit,
+        None => {
+            let mut hasher = HighwayHasher::new(HASHER_SEED);
+            if let Some(parent_id) = data.parent_id {
+                hasher.append(&parent_id.to_be_bytes());
+            }
+            hasher.append(&data.stack_node_data_id.to_be_bytes());
+            hasher.append(&data.executable_id.to_be_bytes());
+            let id = hash_to_id(hasher.finalize64());
+            // should probably restructure this a bit because of 0 id in cache.
+            NODE_ID_CACHE.insert(data.clone(), id);
+            id
+        }
+    };
+    data.id = id;
+}
+
+pub fn id_data(data: &mut StackNodeData) {
+    let id = match DATA_ID_CACHE.get(data) {
+        Some(hit) => hit,
+        None => {
+            let mut hasher = HighwayHasher::new(HASHER_SEED);
+            hasher.append(data.symbol.as_bytes());
+            if let Some(file) = data.clone().file {
+                hasher.append(file.as_bytes());
+            }
+            if let Some(line_number) = data.line_number {
+                hasher.append(&line_number.to_be_bytes());
+            }
+            let id = hash_to_id(hasher.finalize64());
+            // should probably restructure this a bit because of 0 id in cache.
+            DATA_ID_CACHE.insert(data.clone(), id);
+            id
+        }
+    };
+    data.id = id;
+}
+
+// one frame of a stack, symbol as found (it gets demangled on the way in).
+#[derive(Debug, Clone, Hash, Eq, PartialEq)]
+pub struct Frame {
+    pub symbol: String,
+    pub file: Option<String>,
+    pub line_number: Option<i32>,
+}
+
+// what the stacks are a profile of, i.e. everything that goes into the executable row.
+#[derive(Debug, Clone, Default)]
+pub struct ProfileInfo {
+    pub basename: String,
+    pub version: Option<String>,
+    pub event: String,
+    pub labels: BTreeMap<String, String>,
+    pub timestamp: Option<DateTime<Utc>>,
+}
+
+impl ProfileInfo {
+    // basename+version, plus labels sorted so the order they were given in doesn't matter.
+    pub fn executable_id(&self) -> i64 {
+        let mut identity = match self.version.as_ref() {
+            Some(x) => format!("{}{}", self.basename, x),
+            None => self.basename.clone(),
+        };
+        if !self.labels.is_empty() {
+            let label_key: Vec<String> =
+                self.labels.iter().map(|(k, v)| format!("{}={}", k, v)).collect();
+            identity = format!("{}|{}", identity, label_key.join(","));
+        }
+        misc_id(identity)
+    }
+}
+
+// stacks are root first, each w/ how many samples it was seen in. raw_data_size is left for
+// the caller, only it knows what the stacks looked like before this.
+pub fn to_sto_data<I>(info: &ProfileInfo, stacks: I) -> StoData
+where
+    I: IntoIterator<Item = (Vec<Frame>, i64)>,
+{
+    let mut stack_node_map: HashMap<i64, StackNode> = HashMap::new();
+    let mut stack_node_data_map: HashMap<i64, StackNodeData> = HashMap::new();
+    let mut executable = Executable {
+        id: info.executable_id(),
+        event: info.event.clone(),
+        build_id: info.version.clone(),
+        basename: info.basename.clone(),
+        updated_at: None,
+        created_at: None,
+        sample_count: 0,
+        raw_data_size: 0,
+        processed_data_size: 0,
+    };
+
+    for (stack, count) in stacks {
+        if stack.is_empty() || count <= 0 {
+            continue;
+        }
+        // frames, same as what the profiler has always stored.
+        executable.sample_count += count * stack.len() as i64;
+        let mut parent_id: Option<i64> = None;
+        for frame in stack {
+            let mut data = StackNodeData {
+                id: 0,
+                symbol: cached_demangle(&frame.symbol),
+                file: frame.file,
+                line_number: frame.line_number,
+            };
+            id_data(&mut data);
+            let mut stack_node = StackNode {
+                id: 0,
+                parent_id,
+                stack_node_data_id: data.id,
+                executable_id: executable.id,
+                sample_count: 1,
+            };
+            stack_node_data_map.entry(data.id).or_insert(data);
+            id_stack_node(&mut stack_node);
+            stack_node.sample_count = count;
+            parent_id = Some(stack_node.id);
+            stack_node_map
+                .entry(stack_node.id)
+                .and_modify(|e| e.sample_count += count)
+                .or_insert(stack_node);
+        }
+    }
+
+    if executable.sample_count == 0 {
+        return StoData {
+            version: PROTOCOL_VERSION,
+            timestamp: info.timestamp,
+            ..Default::default()
+        };
+    }
+    StoData {
+        version: PROTOCOL_VERSION,
+        timestamp: info.timestamp,
+        stack_nodes: stack_node_map.into_values().collect(),
+        stack_node_datas: stack_node_data_map.into_values().collect(),
+        executable_labels: info
+            .labels
+            .iter()
+            .map(|(key, value)| ExecutableLabel {
+                executable_id: executable.id,
+                key: key.clone(),
+                value: value.clone(),
+            })
+            .collect(),
+        profiled_binaries: vec![executable],
+    }
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImportFormat {
+    PerfScript,
+    Pprof,
+    Folded,
+}
+
+// what came out of a profile from another tool, before it's turned into StoData.
+#[derive(Debug, Clone, Default)]
+pub struct Imported {
+    // defaults from the profile itself, for whatever the caller doesn't say.
+    pub basename: Option<String>,
+    pub version: Option<String>,
+    pub event: Option<String>,
+    pub timestamp: Option<DateTime<Utc>>,
+    // deduped root first stacks, per set of labels the profile put on its samples.
+    pub stacks: BTreeMap<BTreeMap<String, String>, HashMap<Vec<Frame>, i64>>,
+    pub samples: i64,
+    // samples that didn't make it in, e.g. other events or no stack.
+    pub skipped: i64,
+}
+
+impl Imported {
+    // explicit labels win over the profile's own, and every distinct set of labels is its own
+    // executable, same as if each had been profiled separately.
+    pub fn into_sto_data(
+        self,
+        basename: Option<String>,
+        version: Option<String>,
+        labels: &BTreeMap<String, String>,
+        raw_size: i64,
+    ) -> Result<StoData> {
+        let basename = match basename.or(self.basename) {
+            Some(x) => x,
+            None => bail!("no basename given and the profile doesn't have one"),
+        };
+        let version = version.or(self.version);
+        let event = self.event.unwrap_or_else(|| "samples".to_string());
+        let label_sets = self.stacks.len().max(1) as i64;
+        let mut data = StoData {
+            version: PROTOCOL_VERSION,
+            timestamp: self.timestamp,
+            ..Default::default()
+        };
+        for (mut profile_labels, stacks) in self.stacks {
+            profile_labels.extend(labels.clone());
+            let info = ProfileInfo {
+                basename: basename.clone(),
+                version: version.clone(),
+                event: event.clone(),
+                labels: profile_labels,
+                timestamp: self.timestamp,
+            };
+            let mut part = to_sto_data(&info, stacks);
+            for executable in part.profiled_binaries.iter_mut() {
+                executable.raw_data_size = raw_size / label_sets;
+            }
+            data.merge(part);
+        }
+        Ok(data)
+    }
+}
+
+// event means whatever picks or names the samples in that format: the perf event to keep,
+// the pprof sample type to keep, or what to call folded samples. label_keys and max_size only
+// matter to pprof, see there.
+pub fn import(
+    format: ImportFormat,
+    input: &[u8],
+    event: Option<&str>,
+    label_keys: &[String],
+    max_size: u64,
+) -> Result<Imported> {
+    match format {
+        ImportFormat::PerfScript => perf_script(std::str::from_utf8(input)?, event),
+        ImportFormat::Pprof => pprof(input, event, label_keys, max_size),
+        ImportFormat::Folded => folded(std::str::from_utf8(input)?, event),
+    }
+}
+
+// parses `perf script` output, i.e. blank line separated blocks of a header like
+//   comm 1234 [001] 5678.123456:     250000 cycles:u:
+// followed by one indented line per frame, leaf first:
+//   	    7f0e4c6a1b2c do_thing+0x1c (/usr/lib/libfoo.so)
+// w/ `-F +srcline` each frame can be followed by a `file:line` line. only samples of `event`
+// (or the first event seen) are kept, mixing events in one profile makes no sense. basename
+// defaults to the comm of the first sample kept.
+pub fn perf_script(input: &str, event: Option<&str>) -> Result<Imported> {
+    let mut out = Imported {
+        event: event.map(|x| x.to_string()),
+        ..Default::default()
+    };
+    let mut counts: HashMap<Vec<Frame>, i64> = HashMap::new();
+    let mut header: Option<(String, String)> = None;
+    let mut frames: Vec<Frame> = Vec::new();
+    let mut finish = |header: Option<(String, String)>, frames: &mut Vec<Frame>, out: &mut Imported| {
+        let Some((comm, sample_event)) = header else {
+            frames.clear();
+            return;
+        };
+        if out.event.is_none() {
+            out.event = Some(sample_event.clone());
+        }
+        if frames.is_empty() || out.event.as_deref() != Some(sample_event.as_str()) {
+            out.skipped += 1;
+            frames.clear();
+            return;
+        }
+        if out.basename.is_none() {
+            out.basename = Some(comm);
+        }
+        out.samples += 1;
+        let mut stack = std::mem::take(frames);
+        stack.reverse();
+        *counts.entry(stack).or_insert(0) += 1;
+    };
+
+    for (i, line) in input.lines().enumerate() {
+        if line.trim().is_empty() {
+            finish(header.take(), &mut frames, &mut out);
+            continue;
+        }
+        if line.starts_with('#') {
+            continue;
+        }
+        if !line.starts_with(char::is_whitespace) {
+            // a header w/o the blank line before it still starts a new sample.
+            finish(header.take(), &mut frames, &mut out);
+            match parse_header(line) {
+                Some(x) => header = Some(x),
+                None => bail!("line {}: not a perf script sample header: {}", i + 1, line),
+            }
+            continue;
+        }
+        if header.is_none() {
+            bail!("line {}: frame w/o a sample header: {}", i + 1, line);
+        }
+        let line = line.trim();
+        match parse_frame(line) {
+            Some(frame) => frames.push(frame),
+            // srcline of the frame before it. perf prints ??:0 when it doesn't know, skip those.
+            None => match frames.last_mut() {
+                Some(frame) if line.contains(':') => {
+                    if let Some((file, line_number)) = parse_srcline(line) {
+                        frame.file = Some(file);
+                        frame.line_number = Some(line_number);
+                    }
+                }
+                _ => bail!("line {}: not a perf script frame: {}", i + 1, line),
+            },
+        }
+    }
+    finish(header.take(), &mut frames, &mut out);
+
+    if !counts.is_empty() {
+        out.stacks.insert(BTreeMap::new(), counts);
+    }
+    Ok(out)
+}
+
+// (comm, event). comm can have spaces in it so work back from the timestamp, which is the
+// first token that's a number followed by a colon.
+fn parse_header(line: &str) -> Option<(String, String)> {
+    let tokens: Vec<&str> = line.split_whitespace().collect();
+    let ts = tokens.iter().position(|x| {
+        x.strip_suffix(':').map_or(false, |x| x.parse::<f64>().is_ok())
+    })?;
+    let mut comm_end = ts.checked_sub(1)?;
+    if tokens[comm_end].starts_with('[') {
+        comm_end = comm_end.checked_sub(1)?;
+    }
+    let comm = tokens[..comm_end].join(" ");
+    // the period is optional, the event is whatever ends in a colon after it. modifiers like
+    // cycles:u: or cpu-clock:pppH: aren't part of the name.
+    let event = tokens[ts + 1..].iter().find(|x| x.ends_with(':'))?;
+    let event = event.split(':').next()?.to_string();
+    Some((comm, event))
+}
+
+fn parse_frame(line: &str) -> Option<Frame> {
+    let (addr, rest) = line.split_once(char::is_whitespace)?;
+    u64::from_str_radix(addr, 16).ok()?;
+    let rest = rest.trim();
+    let (symbol, dso) = match rest.strip_suffix(')').and_then(|x| x.rsplit_once(" (")) {
+        Some((symbol, dso)) => (symbol.trim(), Some(dso)),
+        None => (rest, None),
+    };
+    let symbol = match symbol.rsplit_once("+0x") {
+        Some((name, off)) if u64::from_str_radix(off, 16).is_ok() => name,
+        _ => symbol,
+    };
+    // same as stackcollapse-perf, unknown frames are at least named after what they're in.
+    let symbol = match (symbol, dso) {
+        ("[unknown]", Some(dso)) | ("", Some(dso)) => {
+            format!("[{}]", dso.rsplit('/').next().unwrap_or(dso))
+        }
+        _ => symbol.to_string(),
+    };
+    Some(Frame {
+        symbol,
+        file: None,
+        line_number: None,
+    })
+}
+
+fn parse_srcline(line: &str) -> Option<(String, i32)> {
+    let (file, line_number) = line.rsplit_once(':')?;
+    let line_number = line_number.trim().parse().ok()?;
+    if file.is_empty() || file.starts_with("??") || line_number == 0 {
+        return None;
+    }
+    Some((file.to_string(), line_number))
+}
+
+// gzipped or not. samples are weighted by one sample type, `sample_type` (type or type/unit,
+// e.g. alloc_space) or the profile's default, and that's what the event is called. units
+// other than count are kept in the name, e.g. cpu/nanoseconds. only string labels w/ a key in
+// label_keys are kept, every distinct set of them ends up its own executable so keeping
+// something like a span id would make one per sample. numeric ones (allocation sizes and such)
+// never are. a gzipped profile can't unpack to more than max_size.
+pub fn pprof(input: &[u8], sample_type: Option<&str>, label_keys: &[String], max_size: u64) -> Result<Imported> {
+    let mut decoded = Vec::new();
+    let input = if input.starts_with(&[0x1f, 0x8b]) {
+        GzDecoder::new(input)
+            .take(max_size.saturating_add(1))
+            .read_to_end(&mut decoded)?;
+        if decoded.len() as u64 > max_size {
+            bail!("decompressed profile exceeds limit of {} bytes", max_size);
+        }
+        &decoded[..]
+    } else {
+        input
+    };
+    let profile = pprof::Profile::decode(input)?;
+    let string = |i: i64| -> &str {
+        profile
+            .string_table
+            .get(i as usize)
+            .map(|x| x.as_str())
+            .unwrap_or("")
+    };
+
+    let types: Vec<String> = profile
+        .sample_type
+        .iter()
+        .map(|x| match string(x.unit) {
+            "" | "count" => string(x.r#type).to_string(),
+            unit => format!("{}/{}", string(x.r#type), unit),
+        })
+        .collect();
+    let idx = match sample_type {
+        Some(want) => types.iter().position(|x| x == want || x.split('/').next() == Some(want)),
+        // the last one is the default when the profile doesn't say.
+        None if profile.default_sample_type != 0 => profile
+            .sample_type
+            .iter()
+            .position(|x| x.r#type == profile.default_sample_type),
+        None => types.len().checked_sub(1),
+    };
+    let Some(idx) = idx else {
+        bail!("no sample type {}, the profile has: {}", sample_type.unwrap_or("at all"), types.join(", "));
+    };
+
+    let functions: HashMap<u64, &pprof::Function> =
+        profile.function.iter().map(|x| (x.id, x)).collect();
+    let mappings: HashMap<u64, &pprof::Mapping> =
+        profile.mapping.iter().map(|x| (x.id, x)).collect();
+    // leaf first, a location w/ inlined functions has the innermost one first.
+    let locations: HashMap<u64, Vec<Frame>> = profile
+        .location
+        .iter()
+        .map(|location| {
+            let mut frames: Vec<Frame> = location
+                .line
+                .iter()
+                .filter_map(|line| {
+                    let function = functions.get(&line.function_id)?;
+                    let symbol = match string(function.name) {
+                        "" => string(function.system_name),
+                        x => x,
+                    };
+                    let file = string(function.filename);
+                    Some(Frame {
+                        symbol: symbol.to_string(),
+                        file: if file.is_empty() { None } else { Some(file.to_string()) },
+                        line_number: if line.line > 0 { Some(line.line as i32) } else { None },
+                    })
+                })
+                .collect();
+            // unsymbolized, named after what it's in like perf script unknowns.
+            if frames.is_empty() {
+                let symbol = match mappings.get(&location.mapping_id).map(|x| string(x.filename)) {
+                    Some(x) if !x.is_empty() => format!("[{}]", x.rsplit('/').next().unwrap_or(x)),
+                    _ => format!("0x{:x}", location.address),
+                };
+                frames.push(Frame {
+                    symbol,
+                    file: None,
+                    line_number: None,
+                });
+            }
+            (location.id, frames)
+        })
+        .collect();
+
+    // the first mapping is the main binary by convention.
+    let main = profile.mapping.first();
+    let mut out = Imported {
+        basename: main
+            .map(|x| string(x.filename))
+            .filter(|x| !x.is_empty())
+            .map(|x| x.rsplit('/').next().unwrap_or(x).to_string()),
+        version: main
+            .map(|x| string(x.build_id))
+            .filter(|x| !x.is_empty())
+            .map(|x| x.to_string()),
+        event: Some(types[idx].clone()),
+        timestamp: match profile.time_nanos {
+            0 => None,
+            x => Some(Utc.timestamp_nanos(x)),
+        },
+        ..Default::default()
+    };
+    for sample in profile.sample.iter() {
+        let value = sample.value.get(idx).copied().unwrap_or(0);
+        let mut stack: Vec<Frame> = sample
+            .location_id
+            .iter()
+            .filter_map(|x| locations.get(x))
+            .flatten()
+            .cloned()
+            .collect();
+        if value <= 0 || stack.is_empty() {
+            out.skipped += 1;
+            continue;
+        }
+        stack.reverse();
+        let labels: BTreeMap<String, String> = sample
+            .label
+            .iter()
+            .filter(|x| x.str != 0 && label_keys.iter().any(|k| k == string(x.key)))
+            .map(|x| (string(x.key).to_string(), string(x.str).to_string()))
+            .collect();
+        out.samples += 1;
+        *out.stacks.entry(labels).or_default().entry(stack).or_insert(0) += value;
+    }
+    Ok(out)
+}
+
+// collapsed stacks, `root;...;leaf count` per line, like stackcollapse-* and --output folded
+// write. frames are just names, there's no file:line to get back out of them.
+pub fn folded(input: &str, event: Option<&str>) -> Result<Imported> {
+    let mut out = Imported {
+        event: event.map(|x| x.to_string()),
+        ..Default::default()
+    };
+    let mut counts: HashMap<Vec<Frame>, i64> = HashMap::new();
+    for (i, line) in input.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let parsed = line
+            .rsplit_once(char::is_whitespace)
+            .and_then(|(stack, count)| Some((stack.trim(), count.parse::<i64>().ok()?)));
+        let Some((stack, count)) = parsed else {
+            bail!("line {}: expected a stack and a count: {}", i + 1, line);
+        };
+        if count <= 0 || stack.is_empty() {
+            out.skipped += 1;
+            continue;
+        }
+        let stack: Vec<Frame> = stack
+            .split(';')
+            .map(|x| Frame {
+                symbol: x.to_string(),
+                file: None,
+                line_number: None,
+            })
+            .collect();
+        out.samples += count;
+        *counts.entry(stack).or_insert(0) += count;
+    }
+    if !counts.is_empty() {
+        out.stacks.insert(BTreeMap::new(), counts);
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(symbol: &str) -> Frame {
+        Frame {
+            symbol: symbol.to_string(),
+            file: None,
+            line_number: None,
+        }
+    }
+
+    fn at(symbol: &str, file: &str, line_number: i32) -> Frame {
+        Frame {
+            symbol: symbol.to_string(),
+            file: Some(file.to_string()),
+            line_number: Some(line_number),
+        }
+    }
+
+    // perf script -F +srcline of a perf record -g, trimmed down.
+    const PERF_SCRIPT: &str = "\
+# ========
+# captured on    : Mon Oct 19 09:00:00 2026
+# ========
+#
+myapp 1234 [001] 5678.123456:     250000 cycles:u: 
+\t    7f0e4c6a1b2c do_thing+0x1c (/usr/lib/libfoo.so)
+  foo.c:42
+\t    55d0c0ffee00 main+0x10 (/usr/bin/myapp)
+  ??:0
+\t    7f0e4c000000 [unknown] (/usr/lib/libc.so.6)
+
+Web Content 99/100 5678.2:     250000 cycles:u: 
+\t    55d0c0ffee00 main+0x10 (/usr/bin/myapp)
+\t    7f0e4c000000 [unknown] (/usr/lib/libc.so.6)
+myapp 1234 [001] 5678.3:     1000 instructions:u: 
+\t    55d0c0ffee00 main+0x10 (/usr/bin/myapp)
+
+myapp 1234 [002] 5678.4:     250000 cycles:u: 
+\t    7f0e4c6a1b2c do_thing+0x1c (/usr/lib/libfoo.so)
+  foo.c:42
+\t    55d0c0ffee00 main+0x10 (/usr/bin/myapp)
+\t    7f0e4c000000 [unknown] (/usr/lib/libc.so.6)
+";
+
+    #[test]
+    fn parse_header_finds_comm_and_event() {
+        assert_eq!(
+            parse_header("myapp 1234 [001] 5678.123456:     250000 cycles:u: "),
+            Some(("myapp".to_string(), "cycles".to_string()))
+        );
+        // no [cpu], multi word comm, pid/tid.
+        assert_eq!(
+            parse_header("Web Content 99/100 5678.2:     250000 cycles:u: "),
+            Some(("Web Content".to_string(), "cycles".to_string()))
+        );
+        // no period either.
+        assert_eq!(
+            parse_header("kworker/0:1 7 [000] 12.5: cpu-clock:pppH: "),
+            Some(("kworker/0:1".to_string(), "cpu-clock".to_string()))
+        );
+        assert_eq!(parse_header("not a header"), None);
+    }
+
+    #[test]
+    fn parse_frame_strips_offsets_and_names_unknowns() {
+        assert_eq!(parse_frame("7f0e4c6a1b2c do_thing+0x1c (/usr/lib/libfoo.so)"), Some(frame("do_thing")));
+        assert_eq!(
+            parse_frame("7f0e4c6a1b2c std::vec::Vec<T>::push (/usr/bin/myapp)"),
+            Some(frame("std::vec::Vec<T>::push"))
+        );
+        assert_eq!(parse_frame("7f0e4c000000 [unknown] (/usr/lib/libc.so.6)"), Some(frame("[libc.so.6]")));
+        assert_eq!(parse_frame("foo.c:42"), None);
+        assert_eq!(parse_frame("nothex do_thing (/x)"), None);
+    }
+
+    #[test]
+    fn parse_srcline_skips_unknowns() {
+        assert_eq!(parse_srcline("foo.c:42"), Some(("foo.c".to_string(), 42)));
+        assert_eq!(parse_srcline("/src/a:b.rs:7"), Some(("/src/a:b.rs".to_string(), 7)));
+        assert_eq!(parse_srcline("??:0"), None);
+        assert_eq!(parse_srcline("??:12"), None);
+        assert_eq!(parse_srcline("foo.c:0"), None);
+        assert_eq!(parse_srcline("foo.c"), None);
+    }
+
+    #[test]
+    fn perf_script_keeps_first_event() {
+        let imported = perf_script(PERF_SCRIPT, None).unwrap();
+        assert_eq!(imported.basename.as_deref(), Some("myapp"));
+        assert_eq!(imported.event.as_deref(), Some("cycles"));
+        assert_eq!(imported.samples, 3);
+        assert_eq!(imported.skipped, 1);
+        let stacks = &imported.stacks[&BTreeMap::new()];
+        assert_eq!(stacks.len(), 2);
+        assert_eq!(stacks[&vec![frame("[libc.so.6]"), frame("main"), at("do_thing", "foo.c", 42)]], 2);
+        assert_eq!(stacks[&vec![frame("[libc.so.6]"), frame("main")]], 1);
+    }
+
+    #[test]
+    fn perf_script_picks_event() {
+        let imported = perf_script(PERF_SCRIPT, Some("instructions")).unwrap();
+        assert_eq!(imported.samples, 1);
+        assert_eq!(imported.skipped, 3);
+        assert_eq!(imported.stacks[&BTreeMap::new()][&vec![frame("main")]], 1);
+    }
+
+    #[test]
+    fn perf_script_rejects_garbage() {
+        assert!(perf_script("\tdeadbeef main (/x)\n", None).is_err());
+        assert!(perf_script("myapp 1 [000] 1.0: 1 cycles:\n\tnot a frame\n", None).is_err());
+    }
+
+    // two sample types, an inlined call, an unsymbolized location and labels.
+    fn profile() -> pprof::Profile {
+        let strings = [
+            "", "samples", "count", "cpu", "nanoseconds", "/usr/bin/myapp", "abc123", "main",
+            "main.c", "inner", "inlined.h", "outer", "thread", "worker", "span", "42",
+        ];
+        let function = |id, name, filename| pprof::Function {
+            id,
+            name,
+            system_name: name,
+            filename,
+            start_line: 0,
+        };
+        let line = |function_id, line| pprof::Line { function_id, line };
+        let label = |key, str| pprof::Label {
+            key,
+            str,
+            ..Default::default()
+        };
+        pprof::Profile {
+            sample_type: vec![
+                pprof::ValueType { r#type: 1, unit: 2 },
+                pprof::ValueType { r#type: 3, unit: 4 },
+            ],
+            sample: vec![
+                pprof::Sample {
+                    location_id: vec![2, 1],
+                    value: vec![1, 100],
+                    label: vec![label(12, 13), label(14, 15)],
+                },
+                pprof::Sample {
+                    location_id: vec![3, 1],
+                    value: vec![2, 50],
+                    label: vec![label(12, 13)],
+                },
+                pprof::Sample {
+                    location_id: vec![1],
+                    value: vec![0, 0],
+                    label: vec![],
+                },
+            ],
+            mapping: vec![pprof::Mapping {
+                id: 1,
+                filename: 5,
+                build_id: 6,
+                ..Default::default()
+            }],
+            location: vec![
+                pprof::Location {
+                    id: 1,
+                    mapping_id: 1,
+                    line: vec![line(1, 10)],
+                    ..Default::default()
+                },
+                // inner got inlined into outer, innermost first.
+                pprof::Location {
+                    id: 2,
+                    mapping_id: 1,
+                    line: vec![line(2, 3), line(3, 20)],
+                    ..Default::default()
+                },
+                pprof::Location {
+                    id: 3,
+                    mapping_id: 1,
+                    address: 0x1234,
+                    ..Default::default()
+                },
+            ],
+            function: vec![function(1, 7, 8), function(2, 9, 10), function(3, 11, 8)],
+            string_table: strings.iter().map(|x| x.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+
+    fn gzip(input: &[u8]) -> Vec<u8> {
+        use std::io::Write;
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(input).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn pprof_plain_and_gzipped() {
+        let plain = profile().encode_to_vec();
+        for input in [plain.clone(), gzip(&plain)] {
+            let imported = pprof(&input, None, &["thread".to_string()], u64::MAX).unwrap();
+            assert_eq!(imported.basename.as_deref(), Some("myapp"));
+            assert_eq!(imported.version.as_deref(), Some("abc123"));
+            // the last sample type when there's no default.
+            assert_eq!(imported.event.as_deref(), Some("cpu/nanoseconds"));
+            assert_eq!(imported.samples, 2);
+            assert_eq!(imported.skipped, 1);
+            let labels = BTreeMap::from([("thread".to_string(), "worker".to_string())]);
+            assert_eq!(imported.stacks.keys().collect::<Vec<_>>(), vec![&labels]);
+            let stacks = &imported.stacks[&labels];
+            assert_eq!(
+                stacks[&vec![at("main", "main.c", 10), at("outer", "main.c", 20), at("inner", "inlined.h", 3)]],
+                100
+            );
+            assert_eq!(stacks[&vec![at("main", "main.c", 10), frame("[myapp]")]], 50);
+        }
+    }
+
+    #[test]
+    fn pprof_labels_are_opt_in() {
+        let imported = pprof(&profile().encode_to_vec(), Some("samples"), &[], u64::MAX).unwrap();
+        assert_eq!(imported.event.as_deref(), Some("samples"));
+        assert_eq!(imported.stacks.keys().collect::<Vec<_>>(), vec![&BTreeMap::new()]);
+        assert_eq!(imported.stacks[&BTreeMap::new()].values().sum::<i64>(), 3);
+
+        let keys = ["thread".to_string(), "span".to_string()];
+        let imported = pprof(&profile().encode_to_vec(), None, &keys, u64::MAX).unwrap();
+        assert_eq!(imported.stacks.len(), 2);
+    }
+
+    #[test]
+    fn pprof_rejects_bad_input() {
+        let plain = profile().encode_to_vec();
+        assert!(pprof(&gzip(&plain), None, &[], plain.len() as u64 - 1).is_err());
+        assert!(pprof(&gzip(&plain), None, &[], plain.len() as u64).is_ok());
+        assert!(pprof(&plain, Some("alloc_space"), &[], u64::MAX).is_err());
+    }
+
+    #[test]
+    fn folded_adds_up_stacks() {
+        let input = "# comment\nmain;run;work 3\nmain;run 2\n\nmain;run;work 1\nmain;idle 0\n";
+        let imported = folded(input, Some("wall")).unwrap();
+        assert_eq!(imported.event.as_deref(), Some("wall"));
+        assert_eq!(imported.samples, 6);
+        assert_eq!(imported.skipped, 1);
+        let stacks = &imported.stacks[&BTreeMap::new()];
+        assert_eq!(stacks[&vec![frame("main"), frame("run"), frame("work")]], 4);
+        assert_eq!(stacks[&vec![frame("main"), frame("run")]], 2);
+    }
+
+    #[test]
+    fn folded_rejects_bad_counts() {
+        let err = folded("main;run 3\nmain;run lots\n", None).unwrap_err();
+        assert!(err.to_string().starts_with("line 2:"), "{}", err);
+        assert!(folded("main;run\n", None).is_err());
+        assert!(folded("main;run 1.5\n", None).is_err());
+    }
+
+    #[test]
+    fn folded_round_trips_through_export() {
+        let input = "main;idle 7\nmain;run 2\nmain;run;work 4\n";
+        let data = folded(input, None)
+            .unwrap()
+            .into_sto_data(Some("myapp".to_string()), None, &BTreeMap::new(), input.len() as i64)
+            .unwrap();
+        assert_eq!(data.samples(), 13);
+        assert_eq!(data.profiled_binaries[0].sample_count, 2 * 7 + 2 * 2 + 3 * 4);
+        assert_eq!(crate::export::folded(&data.stack_nodes, &data.stack_node_datas, false), input);
+    }
+}
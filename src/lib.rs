@@ -5,7 +5,15 @@ use std::str::FromStr;
 
 #[path = "bpf/bpftune.skel.rs"]
 pub mod bpftune;
+pub mod agent;
+pub mod analysis;
+pub mod dag;
 pub mod defs;
+pub mod export;
+pub mod import;
+pub mod retention;
+pub mod store;
+pub mod wire;
 
 unsafe impl Plain for bpftune_bss_types::stacktrace_event {}
 
@@ -0,0 +1,150 @@
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Duration, Utc};
+use serde_derive::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::Path;
+
+use crate::defs::LabeledExecutable;
+use crate::store::{ExecutableQuery, Resolution, Store};
+
+// config for the server's retention task (STO_RETENTION=retention.toml). every interval_secs,
+// each executable gets the first policy whose basename and labels all match, or the top level
+// if none do.
+//
+//   interval_secs = 3600
+//   raw_days = 7
+//   hourly_days = 30
+//
+//   [[policy]]
+//   basename = "api"
+//   labels = { env = "dev" }
+//   raw_days = 1
+//   keep_days = 14
+//
+// per-minute buckets older than raw_days get summed into hourly ones, those older than
+// hourly_days into daily ones, and executables w/o new samples for keep_days are deleted
+// entirely. whatever a policy leaves unset comes from the top level, unset there is forever.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RetentionConfig {
+    #[serde(default = "default_interval_secs")]
+    pub interval_secs: u64,
+    #[serde(flatten)]
+    pub default: Retention,
+    #[serde(rename = "policy", default)]
+    pub policies: Vec<RetentionPolicy>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct Retention {
+    pub raw_days: Option<u32>,
+    pub hourly_days: Option<u32>,
+    pub keep_days: Option<u32>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RetentionPolicy {
+    pub basename: Option<String>,
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+    #[serde(flatten)]
+    pub retention: Retention,
+}
+
+fn default_interval_secs() -> u64 {
+    3600
+}
+
+impl Retention {
+    // self, w/ whatever it leaves unset taken from other.
+    pub fn or(self, other: Retention) -> Retention {
+        Retention {
+            raw_days: self.raw_days.or(other.raw_days),
+            hourly_days: self.hourly_days.or(other.hourly_days),
+            keep_days: self.keep_days.or(other.keep_days),
+        }
+    }
+
+    // keep_days can be anything, downsampling just never gets to happen if it's the shortest.
+    fn check(&self) -> Result<()> {
+        if let (Some(raw), Some(hourly)) = (self.raw_days, self.hourly_days) {
+            if raw > hourly {
+                bail!("raw_days ({}) can't be more than hourly_days ({})", raw, hourly);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl RetentionPolicy {
+    pub fn matches(&self, executable: &LabeledExecutable) -> bool {
+        self.basename.as_ref().map_or(true, |x| *x == executable.executable.basename)
+            && self.labels.iter().all(|(k, v)| executable.labels.get(k) == Some(v))
+    }
+}
+
+impl RetentionConfig {
+    pub fn load(path: &Path) -> Result<RetentionConfig> {
+        let raw = fs::read_to_string(path)
+            .with_context(|| format!("unable to read retention config {}", path.display()))?;
+        let config: RetentionConfig = toml::from_str(&raw)
+            .with_context(|| format!("unable to parse retention config {}", path.display()))?;
+        if config.interval_secs == 0 {
+            bail!("interval_secs must be at least 1");
+        }
+        config.default.check()?;
+        for policy in config.policies.iter() {
+            policy.retention.or(config.default).check()?;
+        }
+        Ok(config)
+    }
+
+    pub fn retention_for(&self, executable: &LabeledExecutable) -> Retention {
+        match self.policies.iter().find(|x| x.matches(executable)) {
+            Some(x) => x.retention.or(self.default),
+            None => self.default,
+        }
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct RetentionStats {
+    // buckets summed into an hourly or daily one.
+    pub downsampled: u64,
+    pub deleted: u64,
+    // frames nothing pointed at anymore.
+    pub collected: u64,
+}
+
+// one pass over everything stored, as of now.
+pub async fn enforce(store: &dyn Store, config: &RetentionConfig, now: DateTime<Utc>) -> Result<RetentionStats> {
+    let days_ago = |x: u32| now - Duration::days(x as i64);
+    let mut by_retention: HashMap<Retention, Vec<i64>> = HashMap::new();
+    let mut expired: Vec<i64> = Vec::new();
+    for x in store.executables(&ExecutableQuery::default()).await? {
+        let retention = config.retention_for(&x);
+        let last_seen = x.executable.updated_at.or(x.executable.created_at);
+        match (retention.keep_days, last_seen) {
+            (Some(keep), Some(at)) if at < days_ago(keep) => expired.push(x.executable.id),
+            _ => by_retention.entry(retention).or_default().push(x.executable.id),
+        }
+    }
+
+    let mut stats = RetentionStats::default();
+    for (retention, ids) in by_retention.iter() {
+        // cutoffs on whole hours/days, so an hour is never half folded.
+        if let Some(x) = retention.raw_days {
+            let before = Resolution::Hour.trunc(days_ago(x));
+            stats.downsampled += store.downsample(ids, before, Resolution::Hour).await?;
+        }
+        if let Some(x) = retention.hourly_days {
+            let before = Resolution::Day.trunc(days_ago(x));
+            stats.downsampled += store.downsample(ids, before, Resolution::Day).await?;
+        }
+    }
+    if !expired.is_empty() {
+        stats.deleted = store.delete_executables(&expired).await?;
+    }
+    stats.collected = store.collect_node_datas().await?;
+    Ok(stats)
+}
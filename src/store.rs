@@ -0,0 +1,372 @@
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, DurationRound, Utc};
+use serde_json::json;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use tracing::{event, Level};
+
+use crate::defs::{
+    Executable, HashCollision, LabeledExecutable, Regression, StackNode, StackNodeData, StoData,
+};
+
+pub mod memory;
+pub mod postgres;
+pub mod sqlite;
+
+pub use memory::MemoryStore;
+pub use postgres::PgStore;
+pub use sqlite::SqliteStore;
+
+// where profiles live. the server only talks to this, which one is picked by the DATABASE_URL
+// scheme, see connect.
+
+// narrows down which executables get listed. every label (key=value) has to match, and from/to
+// only keep executables w/ samples in the window.
+#[derive(Debug, Clone, Default)]
+pub struct ExecutableQuery {
+    pub basename: Option<String>,
+    pub build_id: Option<String>,
+    pub labels: Vec<(String, String)>,
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+}
+
+impl ExecutableQuery {
+    pub fn has_range(&self) -> bool {
+        self.from.is_some() || self.to.is_some()
+    }
+}
+
+// what findRegressions takes, executables are picked by when they were created.
+#[derive(Debug, Clone)]
+pub struct RegressionQuery {
+    pub from: DateTime<Utc>,
+    pub to: Option<DateTime<Utc>>,
+    pub basename: Option<String>,
+    pub min_samples: i64,
+    pub min_pct_change: f64,
+    pub limit: i64,
+}
+
+// what old sample buckets get rolled up into, see downsample.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Resolution {
+    Hour,
+    Day,
+}
+
+impl Resolution {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Resolution::Hour => "hour",
+            Resolution::Day => "day",
+        }
+    }
+
+    // start of the hour/day `at` is in.
+    pub fn trunc(&self, at: DateTime<Utc>) -> DateTime<Utc> {
+        let size = match self {
+            Resolution::Hour => Duration::hours(1),
+            Resolution::Day => Duration::days(1),
+        };
+        at.duration_trunc(size).expect("bucket out of range")
+    }
+}
+
+#[async_trait]
+pub trait Store: Send + Sync {
+    // upserts a batch, summing sample counts into whatever is already there. samples also land
+    // in a per-minute bucket (of data.timestamp, or now) so they can be looked at by time window.
+    async fn ingest(&self, data: StoData) -> Result<()>;
+
+    async fn collisions(&self) -> Result<Vec<HashCollision>>;
+
+    // every (basename, build_id) stored for the given basenames.
+    async fn builds(&self, basenames: &[String]) -> Result<Vec<(String, Option<String>)>>;
+
+    // marks a build as told to the webhooks. true if this call did it, false if something
+    // already had, so only one of several racing callers goes on to post.
+    async fn claim_notification(&self, basename: &str, build_id: &str) -> Result<bool>;
+
+    // newest first, by created_at. that's when it was first stored (and updated_at when it last
+    // got samples), except in memory: where both go by when it was profiled (if known) so a
+    // saved file shows its own times.
+    async fn executables(&self, query: &ExecutableQuery) -> Result<Vec<LabeledExecutable>>;
+
+    async fn executable(&self, id: i64) -> Result<Option<LabeledExecutable>>;
+
+    // stack nodes of all the given executables. counts come from the buckets if there's a range.
+    async fn nodes(
+        &self,
+        ids: &[i64],
+        from: Option<DateTime<Utc>>,
+        to: Option<DateTime<Utc>>,
+    ) -> Result<Vec<StackNode>>;
+
+    // the frames the given executables' nodes point at.
+    async fn node_datas(&self, ids: &[i64]) -> Result<Vec<StackNodeData>>;
+
+    // sums the given executables' sample buckets from before `before` into one bucket per hour
+    // or day. returns how many buckets got folded into another one.
+    async fn downsample(&self, ids: &[i64], before: DateTime<Utc>, to: Resolution) -> Result<u64>;
+
+    // everything about the given executables, their stack nodes and buckets included.
+    async fn delete_executables(&self, ids: &[i64]) -> Result<u64>;
+
+    // frames no stack node points at anymore, i.e. left over after delete_executables.
+    async fn collect_node_datas(&self) -> Result<u64>;
+
+    // symbols that take up a bigger share of a newer executable's samples than of an older one
+    // w/ the same basename. worked out from the other methods here, postgres does it in sql.
+    async fn regressions(&self, query: &RegressionQuery) -> Result<Vec<Regression>> {
+        let executables: Vec<Executable> = self
+            .executables(&ExecutableQuery {
+                basename: query.basename.clone(),
+                ..Default::default()
+            })
+            .await?
+            .into_iter()
+            .map(|x| x.executable)
+            .filter(|x| x.sample_count > 0)
+            .filter(|x| match x.created_at {
+                Some(at) => at > query.from && query.to.map_or(true, |y| at < y),
+                None => false,
+            })
+            .collect();
+        let ids: Vec<i64> = executables.iter().map(|x| x.id).collect();
+        let datas: HashMap<i64, StackNodeData> = self
+            .node_datas(&ids)
+            .await?
+            .into_iter()
+            .map(|x| (x.id, x))
+            .collect();
+        // samples per frame per executable.
+        let mut samples: HashMap<i64, HashMap<i64, i64>> = HashMap::new();
+        for node in self.nodes(&ids, None, None).await? {
+            *samples
+                .entry(node.executable_id)
+                .or_default()
+                .entry(node.stack_node_data_id)
+                .or_default() += node.sample_count;
+        }
+
+        let mut out: Vec<Regression> = Vec::new();
+        for a in executables.iter() {
+            for b in executables.iter() {
+                if a.basename != b.basename || a.id == b.id || b.created_at <= a.created_at {
+                    continue;
+                }
+                let (Some(a_samples), Some(b_samples)) = (samples.get(&a.id), samples.get(&b.id)) else {
+                    continue;
+                };
+                for (snd_id, a_count) in a_samples.iter() {
+                    let Some(b_count) = b_samples.get(snd_id) else {
+                        continue;
+                    };
+                    if *a_count < query.min_samples || *b_count < query.min_samples {
+                        continue;
+                    }
+                    let a_presence = *a_count as f64 / a.sample_count as f64;
+                    let b_presence = *b_count as f64 / b.sample_count as f64;
+                    let pct_diff = (b_presence - a_presence) / a_presence * 100.0;
+                    if b_presence <= a_presence || pct_diff < query.min_pct_change {
+                        continue;
+                    }
+                    let Some(data) = datas.get(snd_id) else {
+                        continue;
+                    };
+                    out.push(Regression {
+                        basename: a.basename.clone(),
+                        base_id: a.id,
+                        base_build_id: a.build_id.clone(),
+                        target_id: b.id,
+                        target_build_id: b.build_id.clone(),
+                        file: data.file.clone(),
+                        symbol: data.symbol.clone(),
+                        base_samples: *a_count,
+                        target_samples: *b_count,
+                        pct_diff,
+                    });
+                }
+            }
+        }
+        out.sort_by(|a, b| b.pct_diff.total_cmp(&a.pct_diff));
+        out.truncate(query.limit.max(0) as usize);
+        Ok(out)
+    }
+}
+
+// postgres://... or postgresql://..., sqlite:path (sqlite::memory: works too), or memory: for
+// something that's gone on restart.
+pub async fn connect(url: &str) -> Result<Box<dyn Store>> {
+    match url.split_once(':').map(|x| x.0) {
+        Some("postgres") | Some("postgresql") => Ok(Box::new(PgStore::connect(url).await?)),
+        Some("sqlite") => Ok(Box::new(SqliteStore::connect(url).await?)),
+        Some("memory") => Ok(Box::new(MemoryStore::default())),
+        _ => bail!(
+            "don't know what to store things in for DATABASE_URL {}, expected postgres://, sqlite: or memory:",
+            url
+        ),
+    }
+}
+
+static HASH_COLLISIONS: AtomicU64 = AtomicU64::new(0);
+
+// ids are 63 bit hashes computed client side, so two unrelated rows can land on the same id.
+// (kind, id, stored, incoming) of a row whose id is already taken by something else.
+pub struct Collision {
+    pub kind: &'static str,
+    pub id: i64,
+    pub stored: serde_json::Value,
+    pub incoming: serde_json::Value,
+}
+
+// compares what's stored against what's incoming; the stored row wins and the incoming one is
+// dropped from the batch and handed back to be recorded, instead of silently merging samples
+// into the wrong frame. incoming nodes on a dropped frame, and everything below a dropped node,
+// would end up attached to the stored row instead, so those go too. they're recorded as
+// "dependent_stack_node" w/ the collision they hung off (kind and hashed_id) as stored.
+pub fn drop_collisions(
+    data: &mut StoData,
+    stored_snd: &HashMap<i64, StackNodeData>,
+    stored_sn: &HashMap<i64, StackNode>,
+) -> Vec<Collision> {
+    let mut collisions: Vec<Collision> = Vec::new();
+    data.stack_node_datas.retain(|x| match stored_snd.get(&x.id) {
+        Some(stored) if stored != x => {
+            collisions.push(Collision {
+                kind: "stack_node_data",
+                id: x.id,
+                stored: json!(stored),
+                incoming: json!(x),
+            });
+            false
+        }
+        _ => true,
+    });
+    let dropped_snd: HashSet<i64> = collisions.iter().map(|x| x.id).collect();
+
+    // node id -> (kind, id) of the collision that took it out.
+    let mut dropped: HashMap<i64, (&'static str, i64)> = HashMap::new();
+    let mut children: HashMap<i64, Vec<i64>> = HashMap::new();
+    for x in data.stack_nodes.iter() {
+        if let Some(parent_id) = x.parent_id {
+            children.entry(parent_id).or_default().push(x.id);
+        }
+        match stored_sn.get(&x.id) {
+            Some(stored)
+                if (stored.parent_id, stored.stack_node_data_id, stored.executable_id)
+                    != (x.parent_id, x.stack_node_data_id, x.executable_id) =>
+            {
+                collisions.push(Collision {
+                    kind: "stack_node",
+                    id: x.id,
+                    stored: json!(stored),
+                    incoming: json!(x),
+                });
+                dropped.insert(x.id, ("stack_node", x.id));
+            }
+            _ if dropped_snd.contains(&x.stack_node_data_id) => {
+                dropped.insert(x.id, ("stack_node_data", x.stack_node_data_id));
+            }
+            _ => {}
+        }
+    }
+    let mut todo: Vec<i64> = dropped.keys().copied().collect();
+    while let Some(id) = todo.pop() {
+        let cause = dropped[&id];
+        for child in children.get(&id).into_iter().flatten() {
+            if !dropped.contains_key(child) {
+                dropped.insert(*child, cause);
+                todo.push(*child);
+            }
+        }
+    }
+    data.stack_nodes.retain(|x| match dropped.get(&x.id) {
+        None => true,
+        // recorded above already.
+        Some(&(kind, id)) if kind == "stack_node" && id == x.id => false,
+        Some(&(kind, id)) => {
+            collisions.push(Collision {
+                kind: "dependent_stack_node",
+                id: x.id,
+                stored: json!({"kind": kind, "hashed_id": id}),
+                incoming: json!(x),
+            });
+            false
+        }
+    });
+
+    if !collisions.is_empty() {
+        let total = HASH_COLLISIONS.fetch_add(collisions.len() as u64, Ordering::Relaxed)
+            + collisions.len() as u64;
+        for x in collisions.iter() {
+            event!(Level::WARN, "hash collision on {} {}: stored {} incoming {} ({} since startup)", x.kind, x.id, x.stored, x.incoming, total);
+        }
+    }
+    collisions
+}
+
+// start of the minute a batch was sampled in.
+pub fn bucket_of(data: &StoData) -> DateTime<Utc> {
+    data.timestamp
+        .unwrap_or_else(Utc::now)
+        .duration_trunc(Duration::minutes(1))
+        .expect("bucket out of range")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snd(id: i64, symbol: &str) -> StackNodeData {
+        StackNodeData { id, symbol: symbol.to_string(), file: None, line_number: None }
+    }
+
+    fn sn(id: i64, parent_id: Option<i64>, stack_node_data_id: i64) -> StackNode {
+        StackNode { id, parent_id, stack_node_data_id, executable_id: 1, sample_count: 1 }
+    }
+
+    #[test]
+    fn drop_collisions_takes_dependents_along() {
+        // 10 <- 11 <- 12 hang off frame 100, which collides. 20 collides itself, 21 is below it.
+        // 30 is fine.
+        let mut data = StoData {
+            stack_node_datas: vec![snd(100, "incoming"), snd(200, "fine")],
+            stack_nodes: vec![
+                sn(12, Some(11), 200),
+                sn(11, Some(10), 200),
+                sn(10, None, 100),
+                sn(21, Some(20), 200),
+                sn(20, None, 200),
+                sn(30, None, 200),
+            ],
+            ..Default::default()
+        };
+        let stored_snd = HashMap::from([(100, snd(100, "stored"))]);
+        let stored_sn = HashMap::from([(20, sn(20, Some(99), 200))]);
+
+        let collisions = drop_collisions(&mut data, &stored_snd, &stored_sn);
+
+        assert_eq!(data.stack_nodes.iter().map(|x| x.id).collect::<Vec<_>>(), vec![30]);
+        assert_eq!(data.stack_node_datas.iter().map(|x| x.id).collect::<Vec<_>>(), vec![200]);
+        let mut kinds: Vec<(&str, i64)> = collisions.iter().map(|x| (x.kind, x.id)).collect();
+        kinds.sort();
+        assert_eq!(
+            kinds,
+            vec![
+                ("dependent_stack_node", 10),
+                ("dependent_stack_node", 11),
+                ("dependent_stack_node", 12),
+                ("dependent_stack_node", 21),
+                ("stack_node", 20),
+                ("stack_node_data", 100),
+            ]
+        );
+        let x = collisions.iter().find(|x| x.id == 12).unwrap();
+        assert_eq!(x.stored, json!({"kind": "stack_node_data", "hashed_id": 100}));
+        let x = collisions.iter().find(|x| x.id == 21).unwrap();
+        assert_eq!(x.stored, json!({"kind": "stack_node", "hashed_id": 20}));
+    }
+}
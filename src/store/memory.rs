@@ -0,0 +1,263 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::RwLock;
+
+use crate::defs::{HashCollision, LabeledExecutable, StackNode, StackNodeData, StoData};
+use crate::store::{bucket_of, drop_collisions, ExecutableQuery, Resolution, Store};
+
+// everything held in memory, gone on restart. for tests, and for looking at a saved file w/o
+// any setup. samples are bucketed per minute same as in the sql stores, so time ranges mean
+// the same thing here.
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    inner: RwLock<Inner>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    executables: HashMap<i64, LabeledExecutable>,
+    nodes: HashMap<i64, StackNode>,
+    // node ids by executable id.
+    by_executable: HashMap<i64, Vec<i64>>,
+    datas: HashMap<i64, StackNodeData>,
+    // sample counts by node id, then bucket.
+    buckets: HashMap<i64, BTreeMap<DateTime<Utc>, i64>>,
+    collisions: Vec<HashCollision>,
+    // (basename, build_id) the webhooks were told about.
+    notified: HashSet<(String, String)>,
+}
+
+impl MemoryStore {
+    // a saved profile, stamped w/ when it was profiled.
+    pub fn new(data: StoData) -> MemoryStore {
+        let store = MemoryStore::default();
+        store.insert(data);
+        store
+    }
+
+    fn insert(&self, mut data: StoData) {
+        let mut inner = self.inner.write().expect("store lock poisoned");
+        let stored_sn: HashMap<i64, StackNode> = data
+            .stack_nodes
+            .iter()
+            .filter_map(|x| inner.nodes.get(&x.id))
+            .map(|x| (x.id, x.clone()))
+            .collect();
+        let stored_snd: HashMap<i64, StackNodeData> = data
+            .stack_node_datas
+            .iter()
+            .filter_map(|x| inner.datas.get(&x.id))
+            .map(|x| (x.id, x.clone()))
+            .collect();
+        for x in drop_collisions(&mut data, &stored_snd, &stored_sn) {
+            let id = inner.collisions.len() as i64 + 1;
+            inner.collisions.insert(
+                0,
+                HashCollision {
+                    id,
+                    kind: x.kind.to_string(),
+                    hashed_id: x.id,
+                    stored: x.stored,
+                    incoming: x.incoming,
+                    seen_at: Utc::now(),
+                },
+            );
+        }
+
+        let at = data.timestamp.unwrap_or_else(Utc::now);
+        let bucket = bucket_of(&data);
+        for mut executable in data.profiled_binaries {
+            match inner.executables.get_mut(&executable.id) {
+                Some(x) => {
+                    x.executable.sample_count += executable.sample_count;
+                    x.executable.raw_data_size += executable.raw_data_size;
+                    x.executable.processed_data_size += executable.processed_data_size;
+                    x.executable.updated_at = x.executable.updated_at.max(Some(at));
+                }
+                None => {
+                    executable.created_at.get_or_insert(at);
+                    executable.updated_at.get_or_insert(at);
+                    inner.executables.insert(
+                        executable.id,
+                        LabeledExecutable {
+                            executable,
+                            labels: Default::default(),
+                        },
+                    );
+                }
+            }
+        }
+        for label in data.executable_labels {
+            if let Some(x) = inner.executables.get_mut(&label.executable_id) {
+                x.labels.entry(label.key).or_insert(label.value);
+            }
+        }
+        for data in data.stack_node_datas {
+            inner.datas.entry(data.id).or_insert(data);
+        }
+        for node in data.stack_nodes {
+            *inner.buckets.entry(node.id).or_default().entry(bucket).or_default() += node.sample_count;
+            match inner.nodes.get_mut(&node.id) {
+                Some(x) => x.sample_count += node.sample_count,
+                None => {
+                    inner.by_executable.entry(node.executable_id).or_default().push(node.id);
+                    inner.nodes.insert(node.id, node);
+                }
+            }
+        }
+    }
+}
+
+impl Inner {
+    // a node's samples in [from, to), none if it has no buckets in there at all.
+    fn ranged(&self, id: &i64, from: Option<DateTime<Utc>>, to: Option<DateTime<Utc>>) -> Option<i64> {
+        let buckets = self.buckets.get(id)?;
+        let mut in_range = buckets
+            .iter()
+            .filter(|(at, _)| from.map_or(true, |x| **at >= x) && to.map_or(true, |x| **at < x))
+            .peekable();
+        in_range.peek()?;
+        Some(in_range.map(|(_, x)| x).sum())
+    }
+}
+
+#[async_trait]
+impl Store for MemoryStore {
+    async fn ingest(&self, data: StoData) -> Result<()> {
+        self.insert(data);
+        Ok(())
+    }
+
+    async fn collisions(&self) -> Result<Vec<HashCollision>> {
+        Ok(self.inner.read().expect("store lock poisoned").collisions.clone())
+    }
+
+    async fn builds(&self, basenames: &[String]) -> Result<Vec<(String, Option<String>)>> {
+        let inner = self.inner.read().expect("store lock poisoned");
+        let builds: HashSet<(String, Option<String>)> = inner
+            .executables
+            .values()
+            .filter(|x| basenames.contains(&x.executable.basename))
+            .map(|x| (x.executable.basename.clone(), x.executable.build_id.clone()))
+            .collect();
+        Ok(builds.into_iter().collect())
+    }
+
+    async fn claim_notification(&self, basename: &str, build_id: &str) -> Result<bool> {
+        let mut inner = self.inner.write().expect("store lock poisoned");
+        Ok(inner.notified.insert((basename.to_string(), build_id.to_string())))
+    }
+
+    async fn executables(&self, query: &ExecutableQuery) -> Result<Vec<LabeledExecutable>> {
+        let inner = self.inner.read().expect("store lock poisoned");
+        let in_range = |id: &i64| {
+            !query.has_range()
+                || inner
+                    .by_executable
+                    .get(id)
+                    .into_iter()
+                    .flatten()
+                    .any(|x| inner.ranged(x, query.from, query.to).is_some())
+        };
+        let mut out: Vec<LabeledExecutable> = inner
+            .executables
+            .values()
+            .filter(|x| query.basename.as_ref().map_or(true, |y| x.executable.basename == *y))
+            .filter(|x| query.build_id.is_none() || x.executable.build_id == query.build_id)
+            .filter(|x| query.labels.iter().all(|(k, v)| x.labels.get(k) == Some(v)))
+            .filter(|x| in_range(&x.executable.id))
+            .cloned()
+            .collect();
+        out.sort_by_key(|x| std::cmp::Reverse(x.executable.created_at));
+        Ok(out)
+    }
+
+    async fn executable(&self, id: i64) -> Result<Option<LabeledExecutable>> {
+        Ok(self.inner.read().expect("store lock poisoned").executables.get(&id).cloned())
+    }
+
+    async fn nodes(
+        &self,
+        ids: &[i64],
+        from: Option<DateTime<Utc>>,
+        to: Option<DateTime<Utc>>,
+    ) -> Result<Vec<StackNode>> {
+        let inner = self.inner.read().expect("store lock poisoned");
+        let nodes = ids
+            .iter()
+            .filter_map(|x| inner.by_executable.get(x))
+            .flatten()
+            .filter_map(|x| inner.nodes.get(x));
+        if from.is_none() && to.is_none() {
+            return Ok(nodes.cloned().collect());
+        }
+        Ok(nodes
+            .filter_map(|x| {
+                let sample_count = inner.ranged(&x.id, from, to)?;
+                Some(StackNode { sample_count, ..x.clone() })
+            })
+            .collect())
+    }
+
+    async fn node_datas(&self, ids: &[i64]) -> Result<Vec<StackNodeData>> {
+        let inner = self.inner.read().expect("store lock poisoned");
+        let wanted: HashSet<i64> = ids
+            .iter()
+            .filter_map(|x| inner.by_executable.get(x))
+            .flatten()
+            .filter_map(|x| inner.nodes.get(x))
+            .map(|x| x.stack_node_data_id)
+            .collect();
+        Ok(wanted
+            .iter()
+            .filter_map(|x| inner.datas.get(x))
+            .cloned()
+            .collect())
+    }
+
+    async fn downsample(&self, ids: &[i64], before: DateTime<Utc>, to: Resolution) -> Result<u64> {
+        let mut inner = self.inner.write().expect("store lock poisoned");
+        let nodes: Vec<i64> = ids.iter().filter_map(|x| inner.by_executable.get(x)).flatten().copied().collect();
+        let mut moved = 0;
+        for node in nodes {
+            let Some(buckets) = inner.buckets.get_mut(&node) else {
+                continue;
+            };
+            let old: Vec<(DateTime<Utc>, i64)> = buckets.range(..before).map(|(k, v)| (*k, *v)).collect();
+            for (bucket, sample_count) in old {
+                let to_bucket = to.trunc(bucket);
+                if to_bucket != bucket {
+                    moved += 1;
+                    buckets.remove(&bucket);
+                    *buckets.entry(to_bucket).or_default() += sample_count;
+                }
+            }
+        }
+        Ok(moved)
+    }
+
+    async fn delete_executables(&self, ids: &[i64]) -> Result<u64> {
+        let mut inner = self.inner.write().expect("store lock poisoned");
+        let mut deleted = 0;
+        for id in ids {
+            if inner.executables.remove(id).is_some() {
+                deleted += 1;
+            }
+            for node in inner.by_executable.remove(id).unwrap_or_default() {
+                inner.nodes.remove(&node);
+                inner.buckets.remove(&node);
+            }
+        }
+        Ok(deleted)
+    }
+
+    async fn collect_node_datas(&self) -> Result<u64> {
+        let mut inner = self.inner.write().expect("store lock poisoned");
+        let referenced: HashSet<i64> = inner.nodes.values().map(|x| x.stack_node_data_id).collect();
+        let before = inner.datas.len();
+        inner.datas.retain(|id, _| referenced.contains(id));
+        Ok((before - inner.datas.len()) as u64)
+    }
+}
@@ -0,0 +1,393 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use sqlx::migrate::Migrator;
+use sqlx::postgres::PgPoolOptions;
+use sqlx::{Pool, Postgres, QueryBuilder};
+use std::collections::{BTreeMap, HashMap};
+
+use crate::defs::{
+    Executable, ExecutableLabel, HashCollision, LabeledExecutable, Regression, StackNode,
+    StackNodeData, StoData,
+};
+use crate::store::{bucket_of, drop_collisions, ExecutableQuery, RegressionQuery, Resolution, Store};
+
+static MIGRATOR: Migrator = sqlx::migrate!();
+
+const BIND_LIMIT: usize = 65535;
+// rows per insert, the widest (executable) binds 8 params a row.
+const CHUNK_ROWS: usize = BIND_LIMIT / 8;
+
+// frames looked at per collect_node_datas transaction.
+const COLLECT_BATCH: i64 = 10000;
+
+// advisory lock key ("sto"). ingest holds it shared, retention exclusively while it deletes, so
+// nothing is deleted between an ingest writing a frame and the stack nodes pointing at it.
+const RETENTION_LOCK: i64 = 0x73746f;
+
+pub struct PgStore {
+    pool: Pool<Postgres>,
+}
+
+impl PgStore {
+    // connects and brings the schema up to date.
+    pub async fn connect(url: &str) -> Result<PgStore> {
+        let pool = PgPoolOptions::new()
+            .max_connections(100)
+            .connect(url)
+            .await?;
+        MIGRATOR.run(&pool).await?;
+        Ok(PgStore { pool })
+    }
+
+    async fn filter_collisions(&self, data: &mut StoData) -> Result<(), sqlx::Error> {
+        let mut conn = self.pool.acquire().await?;
+        let snd_ids: Vec<i64> = data.stack_node_datas.iter().map(|x| x.id).collect();
+        let stored_snd: HashMap<i64, StackNodeData> = sqlx::query_as::<_, StackNodeData>(
+            "select id, symbol, file, line_number from stack_node_data where id = any($1)",
+        )
+        .bind(&snd_ids)
+        .fetch_all(&mut conn)
+        .await?
+        .into_iter()
+        .map(|x| (x.id, x))
+        .collect();
+        let sn_ids: Vec<i64> = data.stack_nodes.iter().map(|x| x.id).collect();
+        let stored_sn: HashMap<i64, StackNode> = sqlx::query_as::<_, StackNode>(
+            "select * from stack_node where id = any($1)",
+        )
+        .bind(&sn_ids)
+        .fetch_all(&mut conn)
+        .await?
+        .into_iter()
+        .map(|x| (x.id, x))
+        .collect();
+
+        let collisions = drop_collisions(data, &stored_snd, &stored_sn);
+        if collisions.is_empty() {
+            return Ok(());
+        }
+        for chunk in collisions.chunks(CHUNK_ROWS) {
+            let mut qb: QueryBuilder<Postgres> =
+                QueryBuilder::new("insert into hash_collision(kind, hashed_id, stored, incoming) ");
+            qb.push_values(chunk, |mut b, x| {
+                b.push_bind(x.kind)
+                    .push_bind(x.id)
+                    .push_bind(x.stored.clone())
+                    .push_bind(x.incoming.clone());
+            });
+            qb.build().execute(&mut conn).await?;
+        }
+        Ok(())
+    }
+}
+
+// appends ` and ...` conditions on a sample_bucket aliased as `b`.
+fn push_range(
+    qb: &mut QueryBuilder<Postgres>,
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+) {
+    if let Some(x) = from {
+        qb.push(" and b.bucket >= ").push_bind(x);
+    }
+    if let Some(x) = to {
+        qb.push(" and b.bucket < ").push_bind(x);
+    }
+}
+
+// appends ` and ...` conditions on an executable aliased as `e`.
+fn push_conditions(qb: &mut QueryBuilder<Postgres>, query: &ExecutableQuery) {
+    if let Some(x) = query.basename.as_ref() {
+        qb.push(" and e.basename = ").push_bind(x.clone());
+    }
+    if let Some(x) = query.build_id.as_ref() {
+        qb.push(" and e.build_id = ").push_bind(x.clone());
+    }
+    for (key, value) in query.labels.iter() {
+        qb.push(" and exists (select 1 from executable_label l where l.executable_id = e.id and l.key = ")
+            .push_bind(key.clone())
+            .push(" and l.value = ")
+            .push_bind(value.clone())
+            .push(")");
+    }
+    if query.has_range() {
+        qb.push(" and exists (select 1 from sample_bucket b where b.executable_id = e.id");
+        push_range(qb, query.from, query.to);
+        qb.push(")");
+    }
+}
+
+#[async_trait]
+impl Store for PgStore {
+    async fn ingest(&self, mut deser_data: StoData) -> Result<()> {
+        self.filter_collisions(&mut deser_data).await?;
+        let bucket = bucket_of(&deser_data);
+        // one transaction, so retention can't delete frames or executables this is about to
+        // point stack nodes at. see RETENTION_LOCK.
+        let mut tx = self.pool.begin().await?;
+        sqlx::query("select pg_advisory_xact_lock_shared($1)")
+            .bind(RETENTION_LOCK)
+            .execute(&mut tx)
+            .await?;
+
+        for chunk in deser_data.stack_node_datas.chunks(CHUNK_ROWS) {
+            let mut qb_1: QueryBuilder<Postgres> = QueryBuilder::new(
+                "insert into stack_node_data(id, symbol, file, line_number) "
+            );
+            qb_1.push_values(chunk, |mut b, snd| {
+                b.push_bind(snd.id)
+                    .push_bind(snd.symbol.clone())
+                    .push_bind(snd.file.clone())
+                    .push_bind(snd.line_number);
+            });
+            qb_1.push(" ON CONFLICT DO NOTHING ");
+            qb_1.build().execute(&mut tx).await?;
+        }
+
+        let updated_at = chrono::Utc::now();
+        for chunk in deser_data.profiled_binaries.chunks(CHUNK_ROWS) {
+            let mut qb_3: QueryBuilder<Postgres> = QueryBuilder::new(
+                "insert into executable(id, event, build_id, basename, updated_at, sample_count, raw_data_size, processed_data_size) "
+            );
+            qb_3.push_values(chunk, |mut b, pb| {
+                b.push_bind(pb.id)
+                    .push_bind(pb.event.clone())
+                    .push_bind(pb.build_id.clone())
+                    .push_bind(pb.basename.clone())
+                    .push_bind(updated_at)
+                    .push_bind(pb.sample_count)
+                    .push_bind(pb.raw_data_size)
+                    .push_bind(pb.processed_data_size);
+            });
+            qb_3.push(" ON CONFLICT (id) DO UPDATE SET sample_count = executable.sample_count + excluded.sample_count, updated_at = excluded.updated_at, raw_data_size = executable.raw_data_size + excluded.raw_data_size, processed_data_size = executable.processed_data_size + excluded.processed_data_size ");
+            qb_3.build().execute(&mut tx).await?;
+        }
+
+        for chunk in deser_data.executable_labels.chunks(CHUNK_ROWS) {
+            let mut qb_5: QueryBuilder<Postgres> = QueryBuilder::new(
+                "insert into executable_label(executable_id, key, value) "
+            );
+            qb_5.push_values(chunk, |mut b, el| {
+                b.push_bind(el.executable_id)
+                    .push_bind(el.key.clone())
+                    .push_bind(el.value.clone());
+            });
+            qb_5.push(" ON CONFLICT DO NOTHING ");
+            qb_5.build().execute(&mut tx).await?;
+        }
+
+        for chunk in deser_data.stack_nodes.chunks(CHUNK_ROWS) {
+            let mut qb_2: QueryBuilder<Postgres> = QueryBuilder::new(
+                "insert into stack_node(id, parent_id, stack_node_data_id, executable_id, sample_count) "
+            );
+            qb_2.push_values(chunk, |mut b, sn| {
+                b.push_bind(sn.id)
+                    .push_bind(sn.parent_id)
+                    .push_bind(sn.stack_node_data_id)
+                    .push_bind(sn.executable_id)
+                    .push_bind(sn.sample_count);
+            });
+            qb_2.push(" ON CONFLICT (id) DO UPDATE SET sample_count = stack_node.sample_count + excluded.sample_count ");
+            qb_2.build().execute(&mut tx).await?;
+
+            let mut qb_4: QueryBuilder<Postgres> = QueryBuilder::new(
+                "insert into sample_bucket(executable_id, stack_node_id, bucket, sample_count) "
+            );
+            qb_4.push_values(chunk, |mut b, sn| {
+                b.push_bind(sn.executable_id)
+                    .push_bind(sn.id)
+                    .push_bind(bucket)
+                    .push_bind(sn.sample_count);
+            });
+            qb_4.push(" ON CONFLICT (stack_node_id, bucket) DO UPDATE SET sample_count = sample_bucket.sample_count + excluded.sample_count ");
+            qb_4.build().execute(&mut tx).await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn collisions(&self) -> Result<Vec<HashCollision>> {
+        let mut conn = self.pool.acquire().await?;
+        Ok(sqlx::query_as::<_, HashCollision>("select * from hash_collision order by seen_at desc")
+            .fetch_all(&mut conn)
+            .await?)
+    }
+
+    async fn builds(&self, basenames: &[String]) -> Result<Vec<(String, Option<String>)>> {
+        let mut conn = self.pool.acquire().await?;
+        Ok(sqlx::query_as("select distinct basename, build_id from executable where basename = any($1)")
+            .bind(basenames)
+            .fetch_all(&mut conn)
+            .await?)
+    }
+
+    async fn claim_notification(&self, basename: &str, build_id: &str) -> Result<bool> {
+        let mut conn = self.pool.acquire().await?;
+        let result = sqlx::query("insert into build_notification(basename, build_id) values ($1, $2) on conflict do nothing")
+            .bind(basename)
+            .bind(build_id)
+            .execute(&mut conn)
+            .await?;
+        Ok(result.rows_affected() == 1)
+    }
+
+    async fn executables(&self, query: &ExecutableQuery) -> Result<Vec<LabeledExecutable>> {
+        let mut conn = self.pool.acquire().await?;
+        let mut qb: QueryBuilder<Postgres> = QueryBuilder::new("select e.* from executable e where true");
+        push_conditions(&mut qb, query);
+        qb.push(" order by e.created_at desc");
+        let pb: Vec<Executable> = qb.build_query_as::<Executable>()
+            .fetch_all(&mut conn)
+            .await?;
+        let ids: Vec<i64> = pb.iter().map(|x| x.id).collect();
+        let mut labels: HashMap<i64, BTreeMap<String, String>> = HashMap::new();
+        for el in sqlx::query_as::<_, ExecutableLabel>("select * from executable_label where executable_id = any($1)")
+            .bind(&ids)
+            .fetch_all(&mut conn)
+            .await? {
+            labels.entry(el.executable_id).or_default().insert(el.key, el.value);
+        }
+        Ok(pb.into_iter().map(|x| LabeledExecutable { labels: labels.remove(&x.id).unwrap_or_default(), executable: x }).collect())
+    }
+
+    async fn executable(&self, id: i64) -> Result<Option<LabeledExecutable>> {
+        let mut conn = self.pool.acquire().await?;
+        let executable = match sqlx::query_as::<_, Executable>("select * from executable where id = $1")
+            .bind(id)
+            .fetch_optional(&mut conn)
+            .await? {
+            Some(x) => x,
+            None => return Ok(None),
+        };
+        let labels = sqlx::query_as::<_, ExecutableLabel>("select * from executable_label where executable_id = $1")
+            .bind(id)
+            .fetch_all(&mut conn)
+            .await?
+            .into_iter()
+            .map(|x| (x.key, x.value))
+            .collect();
+        Ok(Some(LabeledExecutable { executable, labels }))
+    }
+
+    async fn nodes(
+        &self,
+        ids: &[i64],
+        from: Option<DateTime<Utc>>,
+        to: Option<DateTime<Utc>>,
+    ) -> Result<Vec<StackNode>> {
+        let mut conn = self.pool.acquire().await?;
+        let has_range = from.is_some() || to.is_some();
+        let mut qb: QueryBuilder<Postgres> = match has_range {
+            true => QueryBuilder::new(
+                "select n.id, n.parent_id, n.stack_node_data_id, n.executable_id, sum(b.sample_count)::bigint as sample_count \
+                 from stack_node n inner join sample_bucket b on b.stack_node_id = n.id where true",
+            ),
+            false => QueryBuilder::new("select n.* from stack_node n where true"),
+        };
+        qb.push(" and n.executable_id = any(").push_bind(ids.to_vec()).push(")");
+        if has_range {
+            push_range(&mut qb, from, to);
+            qb.push(" group by n.id");
+        }
+        Ok(qb.build_query_as::<StackNode>()
+            .fetch_all(&mut conn)
+            .await?)
+    }
+
+    async fn node_datas(&self, ids: &[i64]) -> Result<Vec<StackNodeData>> {
+        let mut conn = self.pool.acquire().await?;
+        Ok(sqlx::query_as::<_, StackNodeData>(
+            "select distinct d.id, d.symbol, d.file, d.line_number from stack_node_data d \
+             inner join stack_node n on n.stack_node_data_id = d.id where n.executable_id = any($1)",
+        )
+        .bind(ids.to_vec())
+        .fetch_all(&mut conn)
+        .await?)
+    }
+
+    async fn regressions(&self, query: &RegressionQuery) -> Result<Vec<Regression>> {
+        let mut conn = self.pool.acquire().await?;
+        Ok(sqlx::query_as::<_, Regression>(
+            "select * from findRegressions($1, $2, $3, $4, $5) limit $6",
+        )
+        .bind(query.from)
+        .bind(query.to)
+        .bind(query.basename.clone())
+        .bind(query.min_samples)
+        .bind(query.min_pct_change)
+        .bind(query.limit)
+        .fetch_all(&mut conn)
+        .await?)
+    }
+
+    async fn downsample(&self, ids: &[i64], before: DateTime<Utc>, to: Resolution) -> Result<u64> {
+        let mut conn = self.pool.acquire().await?;
+        // buckets already on an hour/day boundary stay put and get the rest summed into them.
+        let (moved,): (i64,) = sqlx::query_as(
+            "with moved as ( \
+                 delete from sample_bucket where executable_id = any($1) and bucket < $2 \
+                 and bucket <> date_trunc($3, bucket at time zone 'UTC') at time zone 'UTC' \
+                 returning executable_id, stack_node_id, date_trunc($3, bucket at time zone 'UTC') at time zone 'UTC' as bucket, sample_count \
+             ), merged as ( \
+                 insert into sample_bucket(executable_id, stack_node_id, bucket, sample_count) \
+                 select executable_id, stack_node_id, bucket, sum(sample_count)::bigint from moved \
+                 group by executable_id, stack_node_id, bucket \
+                 on conflict (stack_node_id, bucket) do update set sample_count = sample_bucket.sample_count + excluded.sample_count \
+             ) \
+             select count(*) from moved",
+        )
+        .bind(ids)
+        .bind(before)
+        .bind(to.as_str())
+        .fetch_one(&mut conn)
+        .await?;
+        Ok(moved as u64)
+    }
+
+    async fn delete_executables(&self, ids: &[i64]) -> Result<u64> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query("select pg_advisory_xact_lock($1)")
+            .bind(RETENTION_LOCK)
+            .execute(&mut tx)
+            .await?;
+        let deleted = sqlx::query("delete from executable where id = any($1)")
+            .bind(ids)
+            .execute(&mut tx)
+            .await?
+            .rows_affected();
+        tx.commit().await?;
+        Ok(deleted)
+    }
+
+    // a batch of frames at a time (by id), each in its own transaction, so ingest only ever
+    // waits on one batch rather than a pass over the whole table.
+    async fn collect_node_datas(&self) -> Result<u64> {
+        let mut collected: u64 = 0;
+        let mut after: Option<i64> = None;
+        loop {
+            let mut tx = self.pool.begin().await?;
+            sqlx::query("select pg_advisory_xact_lock($1)")
+                .bind(RETENTION_LOCK)
+                .execute(&mut tx)
+                .await?;
+            let (last, deleted): (Option<i64>, i64) = sqlx::query_as(
+                "with batch as (select id from stack_node_data where ($1::bigint is null or id > $1) order by id limit $2), \
+                 deleted as (delete from stack_node_data d using batch b where d.id = b.id \
+                     and not exists (select 1 from stack_node n where n.stack_node_data_id = d.id) returning d.id) \
+                 select (select max(id) from batch), (select count(*) from deleted)",
+            )
+            .bind(after)
+            .bind(COLLECT_BATCH)
+            .fetch_one(&mut tx)
+            .await?;
+            tx.commit().await?;
+            collected += deleted as u64;
+            match last {
+                Some(x) => after = Some(x),
+                None => break,
+            }
+        }
+        Ok(collected)
+    }
+}
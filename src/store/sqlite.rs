@@ -0,0 +1,392 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use sqlx::migrate::Migrator;
+use sqlx::sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions};
+use sqlx::{Pool, QueryBuilder, Sqlite};
+use std::collections::{BTreeMap, HashMap};
+use std::str::FromStr;
+
+use crate::defs::{
+    Executable, ExecutableLabel, HashCollision, LabeledExecutable, StackNode, StackNodeData,
+    StoData,
+};
+use crate::store::{bucket_of, drop_collisions, ExecutableQuery, Resolution, Store};
+
+static MIGRATOR: Migrator = sqlx::migrate!("./migrations_sqlite");
+
+// sqlite allows 32766 bound params per statement, rows are chunked to stay well under it.
+const CHUNK_ROWS: usize = 4096;
+
+// single node installs, a file next to the server instead of a postgres to run.
+pub struct SqliteStore {
+    pool: Pool<Sqlite>,
+}
+
+impl SqliteStore {
+    // sqlite:path/to/sto.db, created if it doesn't exist. sqlite::memory: is one db per
+    // connection, so that gets a single connection.
+    pub async fn connect(url: &str) -> Result<SqliteStore> {
+        let options = SqliteConnectOptions::from_str(url)?
+            .create_if_missing(true)
+            .foreign_keys(true)
+            .journal_mode(SqliteJournalMode::Wal);
+        let max_connections = if url.contains(":memory:") { 1 } else { 8 };
+        let pool = SqlitePoolOptions::new()
+            .max_connections(max_connections)
+            .connect_with(options)
+            .await?;
+        MIGRATOR.run(&pool).await?;
+        Ok(SqliteStore { pool })
+    }
+}
+
+// `(?, ?, ...)` for an in list. sqlite is fine w/ an empty one.
+fn push_ids(qb: &mut QueryBuilder<Sqlite>, ids: &[i64]) {
+    qb.push("(");
+    let mut separated = qb.separated(", ");
+    for id in ids {
+        separated.push_bind(*id);
+    }
+    separated.push_unseparated(")");
+}
+
+fn push_range(qb: &mut QueryBuilder<Sqlite>, from: Option<DateTime<Utc>>, to: Option<DateTime<Utc>>) {
+    if let Some(x) = from {
+        qb.push(" and b.bucket >= ").push_bind(x);
+    }
+    if let Some(x) = to {
+        qb.push(" and b.bucket < ").push_bind(x);
+    }
+}
+
+#[async_trait]
+impl Store for SqliteStore {
+    async fn ingest(&self, mut data: StoData) -> Result<()> {
+        let mut conn = self.pool.acquire().await?;
+        let mut stored_snd: HashMap<i64, StackNodeData> = HashMap::new();
+        for chunk in data.stack_node_datas.chunks(CHUNK_ROWS) {
+            let ids: Vec<i64> = chunk.iter().map(|x| x.id).collect();
+            let mut qb: QueryBuilder<Sqlite> =
+                QueryBuilder::new("select id, symbol, file, line_number from stack_node_data where id in ");
+            push_ids(&mut qb, &ids);
+            for x in qb.build_query_as::<StackNodeData>().fetch_all(&mut conn).await? {
+                stored_snd.insert(x.id, x);
+            }
+        }
+        let mut stored_sn: HashMap<i64, StackNode> = HashMap::new();
+        for chunk in data.stack_nodes.chunks(CHUNK_ROWS) {
+            let ids: Vec<i64> = chunk.iter().map(|x| x.id).collect();
+            let mut qb: QueryBuilder<Sqlite> = QueryBuilder::new("select * from stack_node where id in ");
+            push_ids(&mut qb, &ids);
+            for x in qb.build_query_as::<StackNode>().fetch_all(&mut conn).await? {
+                stored_sn.insert(x.id, x);
+            }
+        }
+        let collisions = drop_collisions(&mut data, &stored_snd, &stored_sn);
+        drop(conn);
+
+        let now = Utc::now();
+        let bucket = bucket_of(&data);
+        // all or nothing, and one fsync instead of one per statement.
+        let mut tx = self.pool.begin().await?;
+        for chunk in collisions.chunks(CHUNK_ROWS) {
+            let mut qb: QueryBuilder<Sqlite> =
+                QueryBuilder::new("insert into hash_collision(kind, hashed_id, stored, incoming, seen_at) ");
+            qb.push_values(chunk, |mut b, x| {
+                b.push_bind(x.kind)
+                    .push_bind(x.id)
+                    .push_bind(x.stored.to_string())
+                    .push_bind(x.incoming.to_string())
+                    .push_bind(now);
+            });
+            qb.build().execute(&mut tx).await?;
+        }
+        for chunk in data.stack_node_datas.chunks(CHUNK_ROWS) {
+            let mut qb: QueryBuilder<Sqlite> =
+                QueryBuilder::new("insert into stack_node_data(id, symbol, file, line_number) ");
+            qb.push_values(chunk, |mut b, x| {
+                b.push_bind(x.id)
+                    .push_bind(x.symbol.clone())
+                    .push_bind(x.file.clone())
+                    .push_bind(x.line_number);
+            });
+            qb.push(" on conflict do nothing");
+            qb.build().execute(&mut tx).await?;
+        }
+        for chunk in data.profiled_binaries.chunks(CHUNK_ROWS) {
+            let mut qb: QueryBuilder<Sqlite> = QueryBuilder::new(
+                "insert into executable(id, event, build_id, basename, updated_at, created_at, sample_count, raw_data_size, processed_data_size) ",
+            );
+            qb.push_values(chunk, |mut b, x| {
+                b.push_bind(x.id)
+                    .push_bind(x.event.clone())
+                    .push_bind(x.build_id.clone())
+                    .push_bind(x.basename.clone())
+                    .push_bind(now)
+                    .push_bind(now)
+                    .push_bind(x.sample_count)
+                    .push_bind(x.raw_data_size)
+                    .push_bind(x.processed_data_size);
+            });
+            qb.push(" on conflict (id) do update set sample_count = executable.sample_count + excluded.sample_count, updated_at = excluded.updated_at, raw_data_size = executable.raw_data_size + excluded.raw_data_size, processed_data_size = executable.processed_data_size + excluded.processed_data_size");
+            qb.build().execute(&mut tx).await?;
+        }
+        for chunk in data.executable_labels.chunks(CHUNK_ROWS) {
+            let mut qb: QueryBuilder<Sqlite> =
+                QueryBuilder::new("insert into executable_label(executable_id, key, value) ");
+            qb.push_values(chunk, |mut b, x| {
+                b.push_bind(x.executable_id)
+                    .push_bind(x.key.clone())
+                    .push_bind(x.value.clone());
+            });
+            qb.push(" on conflict do nothing");
+            qb.build().execute(&mut tx).await?;
+        }
+        for chunk in data.stack_nodes.chunks(CHUNK_ROWS) {
+            let mut qb: QueryBuilder<Sqlite> = QueryBuilder::new(
+                "insert into stack_node(id, parent_id, stack_node_data_id, executable_id, sample_count) ",
+            );
+            qb.push_values(chunk, |mut b, x| {
+                b.push_bind(x.id)
+                    .push_bind(x.parent_id)
+                    .push_bind(x.stack_node_data_id)
+                    .push_bind(x.executable_id)
+                    .push_bind(x.sample_count);
+            });
+            qb.push(" on conflict (id) do update set sample_count = stack_node.sample_count + excluded.sample_count");
+            qb.build().execute(&mut tx).await?;
+
+            let mut qb: QueryBuilder<Sqlite> = QueryBuilder::new(
+                "insert into sample_bucket(executable_id, stack_node_id, bucket, sample_count) ",
+            );
+            qb.push_values(chunk, |mut b, x| {
+                b.push_bind(x.executable_id)
+                    .push_bind(x.id)
+                    .push_bind(bucket)
+                    .push_bind(x.sample_count);
+            });
+            qb.push(" on conflict (stack_node_id, bucket) do update set sample_count = sample_bucket.sample_count + excluded.sample_count");
+            qb.build().execute(&mut tx).await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn collisions(&self) -> Result<Vec<HashCollision>> {
+        let mut conn = self.pool.acquire().await?;
+        let rows: Vec<(i64, String, i64, String, String, DateTime<Utc>)> = sqlx::query_as(
+            "select id, kind, hashed_id, stored, incoming, seen_at from hash_collision order by seen_at desc",
+        )
+        .fetch_all(&mut conn)
+        .await?;
+        Ok(rows
+            .into_iter()
+            .map(|(id, kind, hashed_id, stored, incoming, seen_at)| HashCollision {
+                id,
+                kind,
+                hashed_id,
+                stored: serde_json::from_str(&stored).unwrap_or_default(),
+                incoming: serde_json::from_str(&incoming).unwrap_or_default(),
+                seen_at,
+            })
+            .collect())
+    }
+
+    async fn builds(&self, basenames: &[String]) -> Result<Vec<(String, Option<String>)>> {
+        let mut conn = self.pool.acquire().await?;
+        let mut qb: QueryBuilder<Sqlite> =
+            QueryBuilder::new("select distinct basename, build_id from executable where basename in (");
+        let mut separated = qb.separated(", ");
+        for x in basenames {
+            separated.push_bind(x.clone());
+        }
+        separated.push_unseparated(")");
+        Ok(qb.build_query_as::<(String, Option<String>)>()
+            .fetch_all(&mut conn)
+            .await?)
+    }
+
+    async fn claim_notification(&self, basename: &str, build_id: &str) -> Result<bool> {
+        let mut conn = self.pool.acquire().await?;
+        let result = sqlx::query("insert into build_notification(basename, build_id, notified_at) values (?, ?, ?) on conflict do nothing")
+            .bind(basename)
+            .bind(build_id)
+            .bind(Utc::now())
+            .execute(&mut conn)
+            .await?;
+        Ok(result.rows_affected() == 1)
+    }
+
+    async fn executables(&self, query: &ExecutableQuery) -> Result<Vec<LabeledExecutable>> {
+        let mut conn = self.pool.acquire().await?;
+        let mut qb: QueryBuilder<Sqlite> = QueryBuilder::new("select e.* from executable e where true");
+        if let Some(x) = query.basename.as_ref() {
+            qb.push(" and e.basename = ").push_bind(x.clone());
+        }
+        if let Some(x) = query.build_id.as_ref() {
+            qb.push(" and e.build_id = ").push_bind(x.clone());
+        }
+        for (key, value) in query.labels.iter() {
+            qb.push(" and exists (select 1 from executable_label l where l.executable_id = e.id and l.key = ")
+                .push_bind(key.clone())
+                .push(" and l.value = ")
+                .push_bind(value.clone())
+                .push(")");
+        }
+        if query.has_range() {
+            qb.push(" and exists (select 1 from sample_bucket b where b.executable_id = e.id");
+            push_range(&mut qb, query.from, query.to);
+            qb.push(")");
+        }
+        qb.push(" order by e.created_at desc");
+        let pb: Vec<Executable> = qb.build_query_as::<Executable>().fetch_all(&mut conn).await?;
+
+        let mut labels: HashMap<i64, BTreeMap<String, String>> = HashMap::new();
+        for chunk in pb.chunks(CHUNK_ROWS) {
+            let ids: Vec<i64> = chunk.iter().map(|x| x.id).collect();
+            let mut qb: QueryBuilder<Sqlite> =
+                QueryBuilder::new("select * from executable_label where executable_id in ");
+            push_ids(&mut qb, &ids);
+            for el in qb.build_query_as::<ExecutableLabel>().fetch_all(&mut conn).await? {
+                labels.entry(el.executable_id).or_default().insert(el.key, el.value);
+            }
+        }
+        Ok(pb
+            .into_iter()
+            .map(|x| LabeledExecutable {
+                labels: labels.remove(&x.id).unwrap_or_default(),
+                executable: x,
+            })
+            .collect())
+    }
+
+    async fn executable(&self, id: i64) -> Result<Option<LabeledExecutable>> {
+        let mut conn = self.pool.acquire().await?;
+        let executable = match sqlx::query_as::<_, Executable>("select * from executable where id = ?")
+            .bind(id)
+            .fetch_optional(&mut conn)
+            .await?
+        {
+            Some(x) => x,
+            None => return Ok(None),
+        };
+        let labels = sqlx::query_as::<_, ExecutableLabel>("select * from executable_label where executable_id = ?")
+            .bind(id)
+            .fetch_all(&mut conn)
+            .await?
+            .into_iter()
+            .map(|x| (x.key, x.value))
+            .collect();
+        Ok(Some(LabeledExecutable { executable, labels }))
+    }
+
+    async fn nodes(
+        &self,
+        ids: &[i64],
+        from: Option<DateTime<Utc>>,
+        to: Option<DateTime<Utc>>,
+    ) -> Result<Vec<StackNode>> {
+        let mut conn = self.pool.acquire().await?;
+        let has_range = from.is_some() || to.is_some();
+        let mut out: Vec<StackNode> = Vec::new();
+        for chunk in ids.chunks(CHUNK_ROWS) {
+            let mut qb: QueryBuilder<Sqlite> = match has_range {
+                true => QueryBuilder::new(
+                    "select n.id, n.parent_id, n.stack_node_data_id, n.executable_id, sum(b.sample_count) as sample_count \
+                     from stack_node n inner join sample_bucket b on b.stack_node_id = n.id where n.executable_id in ",
+                ),
+                false => QueryBuilder::new("select n.* from stack_node n where n.executable_id in "),
+            };
+            push_ids(&mut qb, chunk);
+            if has_range {
+                push_range(&mut qb, from, to);
+                qb.push(" group by n.id");
+            }
+            out.extend(qb.build_query_as::<StackNode>().fetch_all(&mut conn).await?);
+        }
+        Ok(out)
+    }
+
+    async fn node_datas(&self, ids: &[i64]) -> Result<Vec<StackNodeData>> {
+        let mut conn = self.pool.acquire().await?;
+        let mut out: HashMap<i64, StackNodeData> = HashMap::new();
+        for chunk in ids.chunks(CHUNK_ROWS) {
+            let mut qb: QueryBuilder<Sqlite> = QueryBuilder::new(
+                "select distinct d.id, d.symbol, d.file, d.line_number from stack_node_data d \
+                 inner join stack_node n on n.stack_node_data_id = d.id where n.executable_id in ",
+            );
+            push_ids(&mut qb, chunk);
+            for x in qb.build_query_as::<StackNodeData>().fetch_all(&mut conn).await? {
+                out.insert(x.id, x);
+            }
+        }
+        Ok(out.into_values().collect())
+    }
+
+    // no date functions that'd write timestamps back the way sqlx does, so the summing happens
+    // here. sqlite only has one writer at a time, so nothing lands in between.
+    async fn downsample(&self, ids: &[i64], before: DateTime<Utc>, to: Resolution) -> Result<u64> {
+        let mut tx = self.pool.begin().await?;
+        let mut moved = 0;
+        for chunk in ids.chunks(CHUNK_ROWS) {
+            let mut qb: QueryBuilder<Sqlite> = QueryBuilder::new(
+                "select executable_id, stack_node_id, bucket, sample_count from sample_bucket where executable_id in ",
+            );
+            push_ids(&mut qb, chunk);
+            qb.push(" and bucket < ").push_bind(before);
+            let rows: Vec<(i64, i64, DateTime<Utc>, i64)> = qb.build_query_as().fetch_all(&mut tx).await?;
+            let mut merged: HashMap<(i64, DateTime<Utc>), (i64, i64)> = HashMap::new();
+            for (executable_id, stack_node_id, bucket, sample_count) in rows {
+                let to_bucket = to.trunc(bucket);
+                if to_bucket != bucket {
+                    moved += 1;
+                }
+                merged.entry((stack_node_id, to_bucket)).or_insert((executable_id, 0)).1 += sample_count;
+            }
+
+            let mut qb: QueryBuilder<Sqlite> = QueryBuilder::new("delete from sample_bucket where executable_id in ");
+            push_ids(&mut qb, chunk);
+            qb.push(" and bucket < ").push_bind(before);
+            qb.build().execute(&mut tx).await?;
+            let merged: Vec<((i64, DateTime<Utc>), (i64, i64))> = merged.into_iter().collect();
+            for rows in merged.chunks(CHUNK_ROWS) {
+                let mut qb: QueryBuilder<Sqlite> = QueryBuilder::new(
+                    "insert into sample_bucket(executable_id, stack_node_id, bucket, sample_count) ",
+                );
+                qb.push_values(rows, |mut b, ((stack_node_id, bucket), (executable_id, sample_count))| {
+                    b.push_bind(*executable_id)
+                        .push_bind(*stack_node_id)
+                        .push_bind(*bucket)
+                        .push_bind(*sample_count);
+                });
+                qb.push(" on conflict (stack_node_id, bucket) do update set sample_count = sample_bucket.sample_count + excluded.sample_count");
+                qb.build().execute(&mut tx).await?;
+            }
+        }
+        tx.commit().await?;
+        Ok(moved)
+    }
+
+    async fn delete_executables(&self, ids: &[i64]) -> Result<u64> {
+        let mut tx = self.pool.begin().await?;
+        let mut deleted = 0;
+        for chunk in ids.chunks(CHUNK_ROWS) {
+            let mut qb: QueryBuilder<Sqlite> = QueryBuilder::new("delete from executable where id in ");
+            push_ids(&mut qb, chunk);
+            deleted += qb.build().execute(&mut tx).await?.rows_affected();
+        }
+        tx.commit().await?;
+        Ok(deleted)
+    }
+
+    async fn collect_node_datas(&self) -> Result<u64> {
+        let mut conn = self.pool.acquire().await?;
+        Ok(sqlx::query(
+            "delete from stack_node_data where not exists (select 1 from stack_node n where n.stack_node_data_id = stack_node_data.id)",
+        )
+        .execute(&mut conn)
+        .await?
+        .rows_affected())
+    }
+}
@@ -0,0 +1,167 @@
+use anyhow::{anyhow, Result};
+use clap::ValueEnum;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use serde::de::DeserializeOwned;
+use serde_derive::{Deserialize, Serialize};
+use std::io::{Read, Write};
+
+use std::fmt::Display;
+
+// how data gets from the cli to the server. content negotiation is done w/ the usual
+// content-type and content-encoding headers, so plain json posts keep working.
+
+// files written for uploading later start w/ this, then a container version byte and the
+// format and compression the rest is encoded w/, so they can be read back w/o guessing.
+const FILE_MAGIC: &[u8; 4] = b"STO\0";
+const FILE_VERSION: u8 = 1;
+
+#[derive(
+    ValueEnum, Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, enum_display_derive::Display,
+)]
+pub enum WireFormat {
+    Json,
+    Msgpack,
+}
+
+#[derive(
+    ValueEnum, Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, enum_display_derive::Display,
+)]
+pub enum WireCompression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl WireFormat {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            WireFormat::Json => "application/json",
+            WireFormat::Msgpack => "application/msgpack",
+        }
+    }
+}
+
+impl WireCompression {
+    pub fn content_encoding(&self) -> Option<&'static str> {
+        match self {
+            WireCompression::None => None,
+            WireCompression::Gzip => Some("gzip"),
+            WireCompression::Zstd => Some("zstd"),
+        }
+    }
+
+    pub fn from_content_encoding(encoding: Option<&str>) -> Option<WireCompression> {
+        match encoding.map(|x| x.trim().to_ascii_lowercase()).as_deref() {
+            None | Some("") | Some("identity") => Some(WireCompression::None),
+            Some("gzip") | Some("x-gzip") => Some(WireCompression::Gzip),
+            Some("zstd") => Some(WireCompression::Zstd),
+            Some(_) => None,
+        }
+    }
+}
+
+pub fn encode<T: serde::Serialize>(
+    data: &T,
+    format: WireFormat,
+    compression: WireCompression,
+) -> Result<Vec<u8>> {
+    let serialized = match format {
+        WireFormat::Json => serde_json::to_vec(data)?,
+        WireFormat::Msgpack => rmp_serde::to_vec_named(data)?,
+    };
+    let out = match compression {
+        WireCompression::None => serialized,
+        WireCompression::Gzip => {
+            let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(&serialized)?;
+            encoder.finish()?
+        }
+        WireCompression::Zstd => zstd::encode_all(serialized.as_slice(), 0)?,
+    };
+    Ok(out)
+}
+
+// max_size bounds the decompressed size, so a small compressed body can't blow up the server.
+pub fn decode<T: DeserializeOwned>(
+    bytes: &[u8],
+    format: WireFormat,
+    compression: WireCompression,
+    max_size: u64,
+) -> Result<T> {
+    let mut decompressed = Vec::new();
+    match compression {
+        WireCompression::None => decompressed.extend_from_slice(bytes),
+        WireCompression::Gzip => {
+            GzDecoder::new(bytes)
+                .take(max_size.saturating_add(1))
+                .read_to_end(&mut decompressed)?;
+        }
+        WireCompression::Zstd => {
+            zstd::Decoder::new(bytes)?
+                .take(max_size.saturating_add(1))
+                .read_to_end(&mut decompressed)?;
+        }
+    };
+    if decompressed.len() as u64 > max_size {
+        return Err(anyhow!(
+            "decompressed payload exceeds limit of {} bytes",
+            max_size
+        ));
+    }
+    let out = match format {
+        WireFormat::Json => serde_json::from_slice(&decompressed)?,
+        WireFormat::Msgpack => rmp_serde::from_slice(&decompressed)?,
+    };
+    Ok(out)
+}
+
+pub fn encode_file<T: serde::Serialize>(
+    data: &T,
+    format: WireFormat,
+    compression: WireCompression,
+) -> Result<Vec<u8>> {
+    let mut out = FILE_MAGIC.to_vec();
+    out.push(FILE_VERSION);
+    out.push(match format {
+        WireFormat::Json => 0,
+        WireFormat::Msgpack => 1,
+    });
+    out.push(match compression {
+        WireCompression::None => 0,
+        WireCompression::Gzip => 1,
+        WireCompression::Zstd => 2,
+    });
+    out.extend(encode(data, format, compression)?);
+    Ok(out)
+}
+
+// reads what encode_file writes, or plain uncompressed json/msgpack.
+pub fn decode_file<T: DeserializeOwned>(bytes: &[u8], max_size: u64) -> Result<T> {
+    let Some(rest) = bytes.strip_prefix(FILE_MAGIC) else {
+        let format = match bytes.iter().find(|x| !x.is_ascii_whitespace()) {
+            Some(b'{') => WireFormat::Json,
+            _ => WireFormat::Msgpack,
+        };
+        return decode(bytes, format, WireCompression::None, max_size);
+    };
+    let (header, body) = match rest {
+        [version, format, compression, body @ ..] => ((*version, *format, *compression), body),
+        _ => return Err(anyhow!("truncated file header")),
+    };
+    if header.0 != FILE_VERSION {
+        return Err(anyhow!("file container version {}, this reads {}", header.0, FILE_VERSION));
+    }
+    let format = match header.1 {
+        0 => WireFormat::Json,
+        1 => WireFormat::Msgpack,
+        x => return Err(anyhow!("unknown format {} in file header", x)),
+    };
+    let compression = match header.2 {
+        0 => WireCompression::None,
+        1 => WireCompression::Gzip,
+        2 => WireCompression::Zstd,
+        x => return Err(anyhow!("unknown compression {} in file header", x)),
+    };
+    decode(body, format, compression, max_size)
+}
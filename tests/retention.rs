@@ -0,0 +1,181 @@
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use std::collections::BTreeMap;
+
+use sto::defs::{Executable, LabeledExecutable, StackNode};
+use sto::import::folded;
+use sto::retention::{enforce, Retention, RetentionConfig};
+use sto::store::{connect, ExecutableQuery, Store};
+
+// STO_TEST_DATABASE_URL adds a postgres, it has to be an empty scratch db and the tests have
+// to run one at a time against it (--test-threads 1).
+fn urls() -> Vec<String> {
+    let mut urls = vec!["sqlite::memory:".to_string(), "memory:".to_string()];
+    if let Ok(x) = std::env::var("STO_TEST_DATABASE_URL") {
+        urls.push(x);
+    }
+    urls
+}
+
+fn at(day: u32, hour: u32, minute: u32) -> DateTime<Utc> {
+    Utc.with_ymd_and_hms(2026, 10, day, hour, minute, 0).unwrap()
+}
+
+fn config(toml: &str) -> RetentionConfig {
+    toml::from_str(toml).unwrap()
+}
+
+fn executable(basename: &str, labels: &[(&str, &str)]) -> LabeledExecutable {
+    LabeledExecutable {
+        executable: Executable {
+            id: 1,
+            event: "cycles".to_string(),
+            build_id: None,
+            basename: basename.to_string(),
+            updated_at: None,
+            created_at: None,
+            sample_count: 0,
+            raw_data_size: 0,
+            processed_data_size: 0,
+        },
+        labels: labels.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect(),
+    }
+}
+
+fn roots(nodes: &[StackNode]) -> i64 {
+    nodes.iter().filter(|x| x.parent_id.is_none()).map(|x| x.sample_count).sum()
+}
+
+#[test]
+fn first_matching_policy_wins() {
+    let config = config(
+        r#"
+        raw_days = 7
+        hourly_days = 30
+
+        [[policy]]
+        basename = "api"
+        labels = { env = "dev" }
+        raw_days = 1
+
+        [[policy]]
+        basename = "api"
+        keep_days = 14
+
+        [[policy]]
+        labels = { env = "dev" }
+        hourly_days = 3
+        "#,
+    );
+    let retention = |raw_days, hourly_days, keep_days| Retention {
+        raw_days,
+        hourly_days,
+        keep_days,
+    };
+    assert_eq!(config.interval_secs, 3600);
+    assert_eq!(config.retention_for(&executable("api", &[("env", "dev")])), retention(Some(1), Some(30), None));
+    assert_eq!(config.retention_for(&executable("api", &[("env", "prod")])), retention(Some(7), Some(30), Some(14)));
+    assert_eq!(config.retention_for(&executable("web", &[("env", "dev")])), retention(Some(7), Some(3), None));
+    assert_eq!(config.retention_for(&executable("web", &[])), retention(Some(7), Some(30), None));
+}
+
+#[test]
+fn load_checks_the_config() {
+    let path = std::env::temp_dir().join(format!("sto-retention-{}.toml", std::process::id()));
+    std::fs::write(&path, "raw_days = 10\nhourly_days = 3\n").unwrap();
+    assert!(RetentionConfig::load(&path).is_err());
+    // a policy is checked w/ whatever it gets from the top level.
+    std::fs::write(&path, "hourly_days = 3\n[[policy]]\nbasename = \"api\"\nraw_days = 5\n").unwrap();
+    assert!(RetentionConfig::load(&path).is_err());
+    std::fs::write(&path, "interval_secs = 0\n").unwrap();
+    assert!(RetentionConfig::load(&path).is_err());
+    std::fs::write(&path, "raw_days = 1\nhourly_days = 3\nkeep_days = 2\n").unwrap();
+    assert!(RetentionConfig::load(&path).is_ok());
+    std::fs::remove_file(&path).unwrap();
+}
+
+// one sample a batch, right around the 12:00 and 13:00 boundaries.
+async fn window(store: &dyn Store, id: i64, from: DateTime<Utc>, to: DateTime<Utc>) -> Vec<StackNode> {
+    store.nodes(&[id], Some(from), Some(to)).await.unwrap()
+}
+
+async fn fill(store: &dyn Store) -> i64 {
+    let mut id = 0;
+    for x in [at(1, 11, 59), at(1, 12, 0), at(1, 12, 59), at(1, 13, 0)] {
+        let mut data = folded("main;work 1\n", None)
+            .unwrap()
+            .into_sto_data(Some("app".to_string()), None, &BTreeMap::new(), 0)
+            .unwrap();
+        data.timestamp = Some(x);
+        id = data.profiled_binaries[0].id;
+        store.ingest(data).await.unwrap();
+    }
+    id
+}
+
+#[tokio::test]
+async fn downsampling_keeps_sums_and_boundaries() {
+    let config = config("raw_days = 1\nhourly_days = 30\n");
+    for url in urls() {
+        let store = connect(&url).await.unwrap();
+        let id = fill(store.as_ref()).await;
+
+        // minute buckets a day old get folded into their hour, two per node moved (11:59, 12:59).
+        let stats = enforce(store.as_ref(), &config, at(10, 0, 0)).await.unwrap();
+        assert_eq!((stats.downsampled, stats.deleted, stats.collected), (4, 0, 0), "{}", url);
+        assert_eq!(roots(&store.nodes(&[id], None, None).await.unwrap()), 4, "{}", url);
+        assert_eq!(roots(&window(store.as_ref(), id, at(1, 0, 0), at(2, 0, 0)).await), 4, "{}", url);
+        assert_eq!(roots(&window(store.as_ref(), id, at(1, 11, 0), at(1, 12, 0)).await), 1, "{}", url);
+        assert_eq!(roots(&window(store.as_ref(), id, at(1, 12, 0), at(1, 13, 0)).await), 2, "{}", url);
+        assert_eq!(roots(&window(store.as_ref(), id, at(1, 13, 0), at(1, 14, 0)).await), 1, "{}", url);
+        assert!(window(store.as_ref(), id, at(1, 12, 30), at(1, 13, 0)).await.is_empty(), "{}", url);
+
+        // nothing left to do until the hours get old enough.
+        let stats = enforce(store.as_ref(), &config, at(10, 0, 0)).await.unwrap();
+        assert_eq!(stats.downsampled, 0, "{}", url);
+
+        // then they're folded into their day, all three hours per node.
+        let stats = enforce(store.as_ref(), &config, at(31, 13, 0) + Duration::days(1)).await.unwrap();
+        assert_eq!(stats.downsampled, 6, "{}", url);
+        assert_eq!(roots(&store.nodes(&[id], None, None).await.unwrap()), 4, "{}", url);
+        assert_eq!(roots(&window(store.as_ref(), id, at(1, 0, 0), at(2, 0, 0)).await), 4, "{}", url);
+        assert!(window(store.as_ref(), id, at(1, 12, 0), at(1, 13, 0)).await.is_empty(), "{}", url);
+        assert!(window(store.as_ref(), id, at(2, 0, 0), at(3, 0, 0)).await.is_empty(), "{}", url);
+    }
+}
+
+#[tokio::test]
+async fn enforce_deletes_what_expired() {
+    let config = config("keep_days = 2\n\n[[policy]]\nbasename = \"keep\"\nkeep_days = 10\n");
+    let now = Utc::now();
+    for url in urls() {
+        let store = connect(&url).await.unwrap();
+        // last seen a day ago, or now where that's when it was stored.
+        for (basename, stacks) in [("app", "main;work 1\n"), ("keep", "main;other 1\n")] {
+            let mut data = folded(stacks, None)
+                .unwrap()
+                .into_sto_data(Some(basename.to_string()), None, &BTreeMap::new(), 0)
+                .unwrap();
+            data.timestamp = Some(now - Duration::days(1));
+            store.ingest(data).await.unwrap();
+        }
+
+        let stats = enforce(store.as_ref(), &config, now).await.unwrap();
+        assert_eq!((stats.deleted, stats.collected), (0, 0), "{}", url);
+
+        // main is still used by keep.
+        let stats = enforce(store.as_ref(), &config, now + Duration::days(3)).await.unwrap();
+        assert_eq!((stats.deleted, stats.collected), (1, 1), "{}", url);
+        let left: Vec<String> = store
+            .executables(&ExecutableQuery::default())
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|x| x.executable.basename)
+            .collect();
+        assert_eq!(left, vec!["keep".to_string()], "{}", url);
+
+        let stats = enforce(store.as_ref(), &config, now + Duration::days(11)).await.unwrap();
+        assert_eq!((stats.deleted, stats.collected), (1, 2), "{}", url);
+        assert!(store.executables(&ExecutableQuery::default()).await.unwrap().is_empty(), "{}", url);
+    }
+}
@@ -0,0 +1,108 @@
+use chrono::{DateTime, Duration, Utc};
+use std::collections::{BTreeMap, HashSet};
+
+use sto::defs::{StackNode, StoData};
+use sto::import::folded;
+use sto::store::{connect, ExecutableQuery, RegressionQuery, Store};
+
+// the same checks against every backend that runs w/o setup. STO_TEST_DATABASE_URL adds a
+// postgres to that, it has to be an empty scratch db.
+fn urls() -> Vec<String> {
+    let mut urls = vec!["memory:".to_string(), "sqlite::memory:".to_string()];
+    if let Ok(x) = std::env::var("STO_TEST_DATABASE_URL") {
+        urls.push(x);
+    }
+    urls
+}
+
+fn batch(build: &str, stacks: &str, at: DateTime<Utc>) -> StoData {
+    let mut data = folded(stacks, Some("cycles"))
+        .unwrap()
+        .into_sto_data(Some("app".to_string()), Some(build.to_string()), &BTreeMap::new(), 0)
+        .unwrap();
+    data.timestamp = Some(at);
+    data
+}
+
+fn roots(nodes: &[StackNode]) -> i64 {
+    nodes.iter().filter(|x| x.parent_id.is_none()).map(|x| x.sample_count).sum()
+}
+
+// v1 gets two batches 20 minutes apart, v2 one where `work` takes up a lot more of it.
+async fn fill(store: &dyn Store, now: DateTime<Utc>) -> (i64, i64) {
+    store.ingest(batch("v1", "main;work 50\nmain;idle 50\n", now - Duration::minutes(30))).await.unwrap();
+    store.ingest(batch("v1", "main;work 50\nmain;idle 50\n", now - Duration::minutes(10))).await.unwrap();
+    // created_at is when it was stored for some backends, v2 has to be newer.
+    tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    store.ingest(batch("v2", "main;work 90\nmain;idle 10\n", now - Duration::minutes(5))).await.unwrap();
+    let id = |build: &str| batch(build, "x 1\n", now).profiled_binaries[0].id;
+    (id("v1"), id("v2"))
+}
+
+#[tokio::test]
+async fn stores_agree() {
+    for url in urls() {
+        let store = connect(&url).await.unwrap();
+        let now = Utc::now();
+        let (v1, v2) = fill(store.as_ref(), now).await;
+
+        let all = store.executables(&ExecutableQuery::default()).await.unwrap();
+        let ids: Vec<(i64, i64)> = all.iter().map(|x| (x.executable.id, x.executable.sample_count)).collect();
+        // sample_count counts frames, two a sample here.
+        assert_eq!(ids, vec![(v2, 200), (v1, 400)], "{}", url);
+        let by_build = store
+            .executables(&ExecutableQuery {
+                build_id: Some("v2".to_string()),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(by_build.len(), 1, "{}", url);
+
+        // ranges pick executables w/ samples in them, and narrow counts down to those.
+        let since = |x: i64| ExecutableQuery {
+            from: Some(now - Duration::minutes(x)),
+            ..Default::default()
+        };
+        assert_eq!(store.executables(&since(20)).await.unwrap().len(), 2, "{}", url);
+        assert_eq!(store.executables(&since(7)).await.unwrap().len(), 1, "{}", url);
+        let until = ExecutableQuery {
+            to: Some(now - Duration::minutes(20)),
+            ..Default::default()
+        };
+        let until: Vec<i64> = store.executables(&until).await.unwrap().iter().map(|x| x.executable.id).collect();
+        assert_eq!(until, vec![v1], "{}", url);
+
+        assert_eq!(roots(&store.nodes(&[v1], None, None).await.unwrap()), 200, "{}", url);
+        assert_eq!(roots(&store.nodes(&[v1, v2], None, None).await.unwrap()), 300, "{}", url);
+        let recent = store.nodes(&[v1], Some(now - Duration::minutes(20)), None).await.unwrap();
+        assert_eq!(roots(&recent), 100, "{}", url);
+        assert_eq!(recent.len(), 3, "{}", url);
+        let old = store.nodes(&[v1], None, Some(now - Duration::hours(1))).await.unwrap();
+        assert!(old.is_empty(), "{}", url);
+
+        let symbols: HashSet<String> = store.node_datas(&[v1]).await.unwrap().into_iter().map(|x| x.symbol).collect();
+        assert_eq!(symbols, HashSet::from(["main".to_string(), "work".to_string(), "idle".to_string()]), "{}", url);
+
+        let regressions = store
+            .regressions(&RegressionQuery {
+                from: now - Duration::days(1),
+                to: None,
+                basename: Some("app".to_string()),
+                min_samples: 5,
+                min_pct_change: 10.0,
+                limit: 10,
+            })
+            .await
+            .unwrap();
+        assert_eq!(regressions.len(), 1, "{}: {:?}", url, regressions);
+        let x = &regressions[0];
+        assert_eq!((x.symbol.as_str(), x.base_id, x.target_id), ("work", v1, v2), "{}", url);
+        assert_eq!((x.base_samples, x.target_samples), (100, 90), "{}", url);
+        assert!((x.pct_diff - 80.0).abs() < 0.01, "{}: {}", url, x.pct_diff);
+
+        assert_eq!(store.delete_executables(&[v1, v2]).await.unwrap(), 2, "{}", url);
+        assert_eq!(store.collect_node_datas().await.unwrap(), 3, "{}", url);
+        assert!(store.executables(&ExecutableQuery::default()).await.unwrap().is_empty(), "{}", url);
+    }
+}